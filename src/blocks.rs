@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+
+/// How many recent block hashes we remember to spot reorgs.
+const REORG_WINDOW: u32 = 100;
+
+/// lightningd switched to another chain, dropping our blocks from
+/// `to_height` up to `from_height`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reorg {
+    pub from_height: u32,
+    pub to_height: u32,
+}
+impl Reorg {
+    pub fn depth(&self) -> u32 {
+        self.from_height - self.to_height + 1
+    }
+}
+
+/// The blocks `block_added` told us about. Heights can go back in a
+/// reorg, `safe_height` never does.
+#[derive(Clone, Debug, Default)]
+pub struct Chain {
+    hashes: BTreeMap<u32, String>,
+    tip: u32,
+    safe_height: u32,
+}
+impl Chain {
+    /// Start at `height` from `getinfo`, so cltv checks don't run at height
+    /// 0 until the first `block_added`. We don't know its hash, so it can't
+    /// reveal a reorg.
+    pub fn start_at(&mut self, height: u32) {
+        self.tip = self.tip.max(height);
+        self.safe_height = self.safe_height.max(height);
+    }
+
+    /// Record a block, returns the reorg it reveals if any.
+    pub fn block_added(&mut self, height: u32, hash: &str) -> Option<Reorg> {
+        if self.hashes.get(&height).is_some_and(|h| h == hash) {
+            return None;
+        }
+        let reorg = if !self.hashes.is_empty() && height <= self.tip {
+            Some(Reorg {
+                from_height: self.tip,
+                to_height: height,
+            })
+        } else {
+            None
+        };
+        self.hashes.split_off(&height);
+        self.hashes.insert(height, hash.to_string());
+        self.hashes = self.hashes.split_off(&height.saturating_sub(REORG_WINDOW));
+        self.tip = height;
+        self.safe_height = self.safe_height.max(height);
+        reorg
+    }
+
+    pub fn tip(&self) -> u32 {
+        self.tip
+    }
+
+    /// The highest block we have seen, what cltv decisions are based on.
+    pub fn safe_height(&self) -> u32 {
+        self.safe_height
+    }
+}
+
+/// An htlc is failed once its cltv expiry is within our own `cltv_delta`
+/// plus some slack of `blockheight`.
+pub fn cltv_timed_out(cltv_expiry: u32, blockheight: u32, cltv_delta: u32) -> bool {
+    cltv_expiry <= blockheight + cltv_delta + 6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(height: u32, fork: u8) -> String {
+        format!("{:02x}{:062x}", fork, height)
+    }
+
+    #[test]
+    fn reorg_during_hold_keeps_cltv_safe_height() {
+        let mut chain = Chain::default();
+        for height in 100..=104 {
+            assert_eq!(chain.block_added(height, &hash(height, 0)), None);
+        }
+        // held htlc expires at 150, with cltv_delta 40 it times out at 104
+        assert!(cltv_timed_out(150, chain.safe_height(), 40));
+        assert!(!cltv_timed_out(151, chain.safe_height(), 40));
+
+        // duplicate notification
+        assert_eq!(chain.block_added(104, &hash(104, 0)), None);
+
+        // two blocks are replaced by a fork
+        let reorg = chain.block_added(103, &hash(103, 1)).unwrap();
+        assert_eq!(
+            reorg,
+            Reorg {
+                from_height: 104,
+                to_height: 103
+            }
+        );
+        assert_eq!(reorg.depth(), 2);
+        assert_eq!(chain.tip(), 103);
+        assert_eq!(chain.safe_height(), 104);
+        assert!(cltv_timed_out(150, chain.safe_height(), 40));
+
+        // the fork goes on without further reorgs
+        assert_eq!(chain.block_added(104, &hash(104, 1)), None);
+        assert_eq!(chain.block_added(105, &hash(105, 1)), None);
+        assert_eq!(chain.safe_height(), 105);
+        assert!(cltv_timed_out(151, chain.safe_height(), 40));
+
+        // same height, different hash
+        assert!(chain.block_added(105, &hash(105, 2)).is_some());
+        assert_eq!(chain.safe_height(), 105);
+    }
+
+    #[test]
+    fn reorg_releases_timed_out_htlcs_and_rising_releases_nothing_early() {
+        // held htlcs expiring at 150 and 160 time out at 104 and 114
+        let released = |chain: &Chain| {
+            [150, 160]
+                .into_iter()
+                .filter(|c| cltv_timed_out(*c, chain.safe_height(), 40))
+                .collect::<Vec<_>>()
+        };
+        let mut chain = Chain::default();
+        // at height 0 nothing would ever time out
+        assert!(released(&chain).is_empty());
+        chain.start_at(102);
+        assert_eq!(chain.block_added(103, &hash(103, 0)), None);
+        assert!(released(&chain).is_empty());
+        assert_eq!(chain.block_added(104, &hash(104, 0)), None);
+
+        // a reorg back to 102 before the htlc handler looked again
+        assert!(chain.block_added(102, &hash(102, 1)).is_some());
+        assert_eq!(released(&chain), vec![150]);
+
+        // the fork rises to the old tip and beyond
+        for height in 103..=113 {
+            assert_eq!(chain.block_added(height, &hash(height, 1)), None);
+            assert_eq!(released(&chain), vec![150]);
+        }
+        assert_eq!(chain.block_added(114, &hash(114, 1)), None);
+        assert_eq!(released(&chain), vec![150, 160]);
+    }
+}
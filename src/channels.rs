@@ -0,0 +1,440 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::{info, warn};
+
+use crate::{
+    blocks::cltv_timed_out,
+    exposure::{channel_infos, ChannelInfo},
+    hooks::set_reason,
+    invoices::HtlcKey,
+    maintenance::move_state,
+    rpc::RpcClient,
+    state::{HodlReason, HodlState},
+    stats::Stats,
+    util::listpeers,
+    PluginState,
+};
+
+/// lightningd channel states in which the channel won't come back.
+const CLOSING_STATES: [&str; 6] = [
+    "CHANNELD_SHUTTING_DOWN",
+    "CLOSINGD_SIGEXCHANGE",
+    "CLOSINGD_COMPLETE",
+    "AWAITING_UNILATERAL",
+    "FUNDING_SPEND_SEEN",
+    "ONCHAIN",
+];
+
+/// What to do with a hold whose incoming channel is closing or whose peer
+/// is gone for too long, see `hodl-channel-policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelPolicy {
+    /// Only log a warning.
+    Alert,
+    /// Fail the affected htlc.
+    Fail,
+    /// Cancel the whole hodl-invoice.
+    Cancel,
+}
+impl ChannelPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelPolicy::Alert => "alert",
+            ChannelPolicy::Fail => "fail",
+            ChannelPolicy::Cancel => "cancel",
+        }
+    }
+}
+impl FromStr for ChannelPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ChannelPolicy, Error> {
+        match s.to_lowercase().as_str() {
+            "alert" => Ok(ChannelPolicy::Alert),
+            "fail" => Ok(ChannelPolicy::Fail),
+            "cancel" => Ok(ChannelPolicy::Cancel),
+            _ => Err(anyhow!("policy must be one of alert, fail or cancel")),
+        }
+    }
+}
+
+/// Closing channels and offline peers we learned about from lightningd.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelWatch {
+    closing: BTreeSet<String>,
+    /// Peer id and since when it is disconnected.
+    offline: BTreeMap<String, u64>,
+    /// Htlcs the policy was applied to already.
+    handled: BTreeSet<HtlcKey>,
+}
+impl ChannelWatch {
+    fn is_empty(&self) -> bool {
+        self.closing.is_empty() && self.offline.is_empty()
+    }
+
+    /// Why the policy applies to an htlc held on `scid`, `info` being what
+    /// lightningd knows about that channel. `None` if the htlc is safe.
+    fn at_risk(
+        &self,
+        scid: &str,
+        info: Option<&ChannelInfo>,
+        cltv_expiry: u32,
+        blockheight: u32,
+        now: u64,
+        offline: OfflineLimits,
+    ) -> Option<Risk> {
+        let closing = self.closing.contains(scid)
+            || info.is_some_and(|i| self.closing.contains(&i.short_channel_id));
+        if closing {
+            return Some(Risk::Closing);
+        }
+        let since = *self.offline.get(&info?.peer_id)?;
+        if since + offline.timeout <= now
+            && cltv_timed_out(
+                cltv_expiry,
+                blockheight + offline.blocks as u32,
+                offline.cltv_delta,
+            )
+        {
+            return Some(Risk::Offline(now - since));
+        }
+        None
+    }
+}
+
+/// Why a held htlc is at risk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Risk {
+    Closing,
+    /// Seconds the peer is offline.
+    Offline(u64),
+}
+impl Risk {
+    /// What a hodl-invoice canceled for it gets as `reason`.
+    fn reason(&self) -> HodlReason {
+        match self {
+            Risk::Closing => HodlReason::ChannelClosing,
+            Risk::Offline(_) => HodlReason::PeerOffline,
+        }
+    }
+}
+impl fmt::Display for Risk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Risk::Closing => write!(f, "incoming channel is closing"),
+            Risk::Offline(secs) => write!(f, "peer offline for {}s", secs),
+        }
+    }
+}
+
+/// When an offline peer puts the htlcs we hold from it at risk, see
+/// `hodl-peer-offline-timeout` and `hodl-peer-offline-blocks`.
+#[derive(Clone, Copy, Debug)]
+struct OfflineLimits {
+    timeout: u64,
+    blocks: u64,
+    cltv_delta: u32,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Start out with the peers that are disconnected right now, we won't
+/// get a `disconnect` notification for them. How long they were gone
+/// before we started is unknown, so they count as offline from now on.
+pub async fn load_channel_watch(rpc: &RpcClient) -> Result<ChannelWatch, Error> {
+    let now = unix_now();
+    Ok(ChannelWatch {
+        offline: listpeers(rpc)
+            .await?
+            .peers
+            .into_iter()
+            .filter(|p| !p.connected)
+            .map(|p| (p.id.to_string(), now))
+            .collect(),
+        ..Default::default()
+    })
+}
+
+/// Forget closing channels lightningd no longer reports, they can't
+/// carry our htlcs anymore.
+pub async fn forget_closed_channels(plugin: &Plugin<PluginState>) {
+    if plugin.state().channels.lock().closing.is_empty() {
+        return;
+    }
+    let infos = match channel_infos(&plugin.state().rpc).await {
+        Ok(i) => i,
+        Err(e) => {
+            warn!("Could not look for closed channels: {}", e);
+            return;
+        }
+    };
+    plugin
+        .state()
+        .channels
+        .lock()
+        .closing
+        .retain(|scid| infos.contains_key(scid));
+}
+
+pub async fn channel_state_changed(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    let change = match v.get("channel_state_changed") {
+        Some(c) => c,
+        None => return Err(anyhow!("could not read channel_state_changed notification")),
+    };
+    let scid = match change.get("short_channel_id").and_then(|s| s.as_str()) {
+        Some(s) => s,
+        // not confirmed yet, so it can't carry our htlcs
+        None => return Ok(()),
+    };
+    let new_state = change
+        .get("new_state")
+        .and_then(|s| s.as_str())
+        .unwrap_or_default();
+    if !CLOSING_STATES.contains(&new_state) {
+        return Ok(());
+    }
+    if plugin
+        .state()
+        .channels
+        .lock()
+        .closing
+        .insert(scid.to_string())
+    {
+        info!("Channel `{}` is closing, State={}", scid, new_state);
+    }
+    check_channels(&plugin).await;
+    Ok(())
+}
+
+pub async fn connect(plugin: Plugin<PluginState>, v: serde_json::Value) -> Result<(), Error> {
+    let peer_id = match v
+        .get("connect")
+        .or_else(|| v.get("id").map(|_| &v))
+        .and_then(|c| c.get("id"))
+        .and_then(|i| i.as_str())
+    {
+        Some(i) => i,
+        None => return Err(anyhow!("could not read connect notification")),
+    };
+    plugin.state().channels.lock().offline.remove(peer_id);
+    Ok(())
+}
+
+pub async fn disconnect(plugin: Plugin<PluginState>, v: serde_json::Value) -> Result<(), Error> {
+    let peer_id = match v
+        .get("disconnect")
+        .or_else(|| v.get("id").map(|_| &v))
+        .and_then(|c| c.get("id"))
+        .and_then(|i| i.as_str())
+    {
+        Some(i) => i,
+        None => return Err(anyhow!("could not read disconnect notification")),
+    };
+    plugin
+        .state()
+        .channels
+        .lock()
+        .offline
+        .entry(peer_id.to_string())
+        .or_insert_with(unix_now);
+    Ok(())
+}
+
+/// Apply `hodl-channel-policy` to htlcs held on a closing channel, or
+/// from a peer offline for `hodl-peer-offline-timeout` seconds once the
+/// htlc is within `hodl-peer-offline-blocks` of its cltv timeout. A held
+/// forward has no hodl-invoice to cancel, so `cancel` fails its htlc too.
+pub async fn check_channels(plugin: &Plugin<PluginState>) {
+    let watch = plugin.state().channels.lock().clone();
+    if watch.is_empty() {
+        return;
+    }
+    // payment hash, htlc, its cltv expiry and whether it is a forward
+    let mut held = Vec::new();
+    for pay_hash in plugin.state().states.pay_hashes() {
+        if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+            for (key, htlc) in entry.htlcs() {
+                if !watch.handled.contains(key) {
+                    held.push((pay_hash.clone(), key.clone(), htlc.cltv_expiry, false));
+                }
+            }
+        }
+    }
+    for (pay_hash, hold) in plugin.state().forwards.lock().iter() {
+        for (key, htlc) in &hold.htlcs {
+            if !watch.handled.contains(key) {
+                held.push((pay_hash.clone(), key.clone(), htlc.cltv_expiry, true));
+            }
+        }
+    }
+    if held.is_empty() {
+        return;
+    }
+    let infos = match channel_infos(&plugin.state().rpc).await {
+        Ok(i) => i,
+        Err(e) => {
+            warn!("Could not check channels of held htlcs: {}", e);
+            return;
+        }
+    };
+    let (policy, offline) = {
+        let config = plugin.state().config.lock();
+        (
+            config.channel_policy.1,
+            OfflineLimits {
+                timeout: config.peer_offline_timeout.1,
+                blocks: config.peer_offline_blocks.1,
+                cltv_delta: config.cltv_delta.1 as u32,
+            },
+        )
+    };
+    let blockheight = *plugin.state().blockheight.lock();
+    let now = unix_now();
+
+    for (pay_hash, key, cltv_expiry, forward) in held {
+        let risk = match watch.at_risk(
+            &key.scid,
+            infos.get(&key.scid),
+            cltv_expiry,
+            blockheight,
+            now,
+            offline,
+        ) {
+            Some(r) => r,
+            None => continue,
+        };
+        plugin.state().channels.lock().handled.insert(key.clone());
+        Stats::inc(&plugin.state().stats.channel_policy);
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. {} at risk, {}. Policy={}",
+            pay_hash,
+            key.scid,
+            key.id,
+            if forward { "Held forward" } else { "Hold" },
+            risk,
+            policy.as_str().to_uppercase()
+        );
+        match policy {
+            ChannelPolicy::Alert => (),
+            ChannelPolicy::Fail | ChannelPolicy::Cancel if forward => {
+                if let Some(htlc) = plugin
+                    .state()
+                    .forwards
+                    .lock()
+                    .get_mut(&pay_hash)
+                    .and_then(|h| h.htlcs.get_mut(&key))
+                {
+                    htlc.release_requested = true;
+                }
+            }
+            ChannelPolicy::Fail => {
+                plugin
+                    .state()
+                    .states
+                    .with_entry(&pay_hash, |e| e.request_release_htlc(&key))
+                    .await;
+            }
+            ChannelPolicy::Cancel => cancel(plugin, &pay_hash, risk.reason()).await,
+        }
+    }
+
+    // forget what isn't held anymore
+    let mut held_keys = BTreeSet::new();
+    for pay_hash in plugin.state().states.pay_hashes() {
+        if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+            held_keys.extend(entry.htlcs().keys().cloned());
+        }
+    }
+    for hold in plugin.state().forwards.lock().values() {
+        held_keys.extend(hold.htlcs.keys().cloned());
+    }
+    plugin
+        .state()
+        .channels
+        .lock()
+        .handled
+        .retain(|k| held_keys.contains(k));
+}
+
+/// Cancel a hodl-invoice for `hodl-channel-policy=cancel`. Like for
+/// expiry, the reason goes first so whoever sees the new state finds it.
+async fn cancel(plugin: &Plugin<PluginState>, pay_hash: &str, reason: HodlReason) {
+    let cancelable = match plugin.state().states.entry(pay_hash).await {
+        Some(entry) => entry.state.is_valid_transition(&HodlState::Canceled),
+        None => false,
+    };
+    if !cancelable {
+        return;
+    }
+    set_reason(plugin, pay_hash, Some(reason)).await;
+    match move_state(plugin, pay_hash, HodlState::Canceled).await {
+        Ok(Some(_)) => Stats::inc(&plugin.state().stats.auto_cancels),
+        Ok(None) => (),
+        Err(e) => warn!("payment_hash: `{}`. Could not cancel: {}", pay_hash, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFLINE: OfflineLimits = OfflineLimits {
+        timeout: 600,
+        blocks: 72,
+        cltv_delta: 40,
+    };
+
+    fn info(scid: &str, peer_id: &str) -> ChannelInfo {
+        ChannelInfo {
+            short_channel_id: scid.to_string(),
+            peer_id: peer_id.to_string(),
+            max_accepted_htlcs: None,
+        }
+    }
+
+    #[test]
+    fn closing_channels_and_long_offline_peers_are_at_risk() {
+        let mut watch = ChannelWatch::default();
+        watch.closing.insert("1x1x1".to_string());
+        watch.offline.insert("b".to_string(), 1_000);
+        let risk = |scid: &str, info: Option<&ChannelInfo>, blockheight: u32, now: u64| {
+            watch.at_risk(scid, info, 800_200, blockheight, now, OFFLINE)
+        };
+
+        // closing, by scid or by the alias the htlc came in on
+        assert_eq!(risk("1x1x1", None, 0, 0), Some(Risk::Closing));
+        assert_eq!(
+            risk("alias", Some(&info("1x1x1", "a")), 0, 0),
+            Some(Risk::Closing)
+        );
+        assert_eq!(
+            risk("2x2x2", Some(&info("2x2x2", "a")), 800_100, 5_000),
+            None
+        );
+        assert_eq!(risk("3x3x3", None, 800_100, 5_000), None);
+
+        // offline long enough and 800_200 <= 800_082 + 72 + 40 + 6
+        let b = info("3x3x3", "b");
+        assert_eq!(
+            risk("3x3x3", Some(&b), 800_082, 1_600),
+            Some(Risk::Offline(600))
+        );
+        assert_eq!(risk("3x3x3", Some(&b), 800_082, 1_599), None);
+        assert_eq!(risk("3x3x3", Some(&b), 800_081, 1_600), None);
+    }
+}
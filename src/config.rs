@@ -1,24 +1,105 @@
 use anyhow::{anyhow, Error};
 use cln_plugin::ConfiguredPlugin;
 use log::warn;
-use std::path::Path;
+use std::{path::Path, str::FromStr};
 
 use tokio::fs;
 
-use crate::PluginState;
+use crate::{
+    channels::ChannelPolicy,
+    keysend::{parse_keysend_records, KeysendRecord, KeysendRules},
+    limits::Limits,
+    PluginState,
+};
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub cltv_delta: (String, u16),
+    pub retention: (String, u64),
+    pub archive: (String, bool),
+    pub settle_deadline: (String, u64),
+    pub webhooks: (String, Vec<String>),
+    pub webhook_secret: (String, Option<String>),
+    pub metrics_port: (String, Option<u16>),
+    pub max_held_msat: (String, Option<u64>),
+    pub max_held_htlcs: (String, Option<u64>),
+    pub max_channel_msat: (String, Option<u64>),
+    pub max_channel_htlcs: (String, Option<u64>),
+    pub max_peer_msat: (String, Option<u64>),
+    pub max_peer_htlcs: (String, Option<u64>),
+    pub channel_policy: (String, ChannelPolicy),
+    pub peer_offline_timeout: (String, u64),
+    pub peer_offline_blocks: (String, u64),
+    pub keysend: (String, bool),
+    pub keysend_min_msat: (String, Option<u64>),
+    pub keysend_max_msat: (String, Option<u64>),
+    pub keysend_records: (String, Vec<KeysendRecord>),
+    pub keysend_expiry: (String, u64),
+    pub forward_min_blocks: (String, u32),
 }
 impl Config {
     pub fn new() -> Config {
         Config {
             cltv_delta: ("cltv-delta".to_string(), 40),
+            retention: ("hodl-retention".to_string(), 3_600),
+            archive: ("hodl-archive".to_string(), false),
+            settle_deadline: ("hodl-settle-deadline".to_string(), 60),
+            webhooks: ("hodl-webhook".to_string(), Vec::new()),
+            webhook_secret: ("hodl-webhook-secret".to_string(), None),
+            metrics_port: ("hodl-metrics-port".to_string(), None),
+            max_held_msat: ("hodl-max-held-msat".to_string(), None),
+            max_held_htlcs: ("hodl-max-held-htlcs".to_string(), None),
+            max_channel_msat: ("hodl-max-channel-msat".to_string(), None),
+            max_channel_htlcs: ("hodl-max-channel-htlcs".to_string(), None),
+            max_peer_msat: ("hodl-max-peer-msat".to_string(), None),
+            max_peer_htlcs: ("hodl-max-peer-htlcs".to_string(), None),
+            channel_policy: ("hodl-channel-policy".to_string(), ChannelPolicy::Alert),
+            peer_offline_timeout: ("hodl-peer-offline-timeout".to_string(), 600),
+            peer_offline_blocks: ("hodl-peer-offline-blocks".to_string(), 72),
+            keysend: ("hodl-keysend".to_string(), false),
+            keysend_min_msat: ("hodl-keysend-min-msat".to_string(), None),
+            keysend_max_msat: ("hodl-keysend-max-msat".to_string(), None),
+            keysend_records: ("hodl-keysend-records".to_string(), Vec::new()),
+            keysend_expiry: ("hodl-keysend-expiry".to_string(), 86_400),
+            forward_min_blocks: ("hodl-forward-min-blocks".to_string(), 18),
+        }
+    }
+
+    pub fn keysend_rules(&self) -> KeysendRules {
+        KeysendRules {
+            enabled: self.keysend.1,
+            min_msat: self.keysend_min_msat.1,
+            max_msat: self.keysend_max_msat.1,
+            records: self.keysend_records.1.clone(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            total_msat: self.max_held_msat.1,
+            total_htlcs: self.max_held_htlcs.1,
+            channel_msat: self.max_channel_msat.1,
+            channel_htlcs: self.max_channel_htlcs.1,
+            peer_msat: self.max_peer_msat.1,
+            peer_htlcs: self.max_peer_htlcs.1,
         }
     }
 }
 
+/// A limit (or any other optional number) is unset if negative.
+fn parse_limit(value: &str, name: &str) -> Result<Option<u64>, Error> {
+    match value.parse::<i64>() {
+        Ok(n) if n < 0 => Ok(None),
+        Ok(n) => Ok(Some(n as u64)),
+        Err(e) => Err(anyhow!(
+            "Error: Could not parse a number from `{}` for {}: {}",
+            value,
+            name,
+            e
+        )),
+    }
+}
+
 pub async fn read_config(
     plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
     state: PluginState,
@@ -37,7 +118,7 @@ pub async fn read_config(
     let mut config = state.config.lock();
     for line in configfile.lines() {
         if line.contains('=') {
-            let splitline = line.split('=').collect::<Vec<&str>>();
+            let splitline = line.splitn(2, '=').collect::<Vec<&str>>();
             if splitline.len() == 2 {
                 let name = splitline.clone().into_iter().nth(0).unwrap();
                 let value = splitline.into_iter().nth(1).unwrap();
@@ -54,11 +135,188 @@ pub async fn read_config(
                             ))
                         }
                     },
+                    opt if opt.eq(&config.retention.0) => match value.parse::<u64>() {
+                        Ok(n) => config.retention.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.retention.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.archive.0) => match value.parse::<bool>() {
+                        Ok(b) => config.archive.1 = b,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a bool from `{}` for {}: {}",
+                                value,
+                                config.archive.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.settle_deadline.0) => match value.parse::<u64>() {
+                        Ok(n) => config.settle_deadline.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.settle_deadline.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.webhooks.0) => {
+                        config.webhooks.1 = value
+                            .split(',')
+                            .map(|url| url.trim().to_string())
+                            .filter(|url| !url.is_empty())
+                            .collect()
+                    }
+                    opt if opt.eq(&config.webhook_secret.0) => {
+                        config.webhook_secret.1 =
+                            Some(value.trim().to_string()).filter(|s| !s.is_empty())
+                    }
+                    opt if opt.eq(&config.metrics_port.0) => match value.parse::<i64>() {
+                        Ok(n) if n < 0 => config.metrics_port.1 = None,
+                        Ok(n) if n <= u16::MAX as i64 => config.metrics_port.1 = Some(n as u16),
+                        Ok(n) => {
+                            return Err(anyhow!(
+                                "Error: {} is not a valid port for {}",
+                                n,
+                                config.metrics_port.0
+                            ))
+                        }
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.metrics_port.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.max_held_msat.0) => {
+                        config.max_held_msat.1 = parse_limit(value, &config.max_held_msat.0)?
+                    }
+                    opt if opt.eq(&config.max_held_htlcs.0) => {
+                        config.max_held_htlcs.1 = parse_limit(value, &config.max_held_htlcs.0)?
+                    }
+                    opt if opt.eq(&config.max_channel_msat.0) => {
+                        config.max_channel_msat.1 = parse_limit(value, &config.max_channel_msat.0)?
+                    }
+                    opt if opt.eq(&config.max_channel_htlcs.0) => {
+                        config.max_channel_htlcs.1 =
+                            parse_limit(value, &config.max_channel_htlcs.0)?
+                    }
+                    opt if opt.eq(&config.max_peer_msat.0) => {
+                        config.max_peer_msat.1 = parse_limit(value, &config.max_peer_msat.0)?
+                    }
+                    opt if opt.eq(&config.max_peer_htlcs.0) => {
+                        config.max_peer_htlcs.1 = parse_limit(value, &config.max_peer_htlcs.0)?
+                    }
+                    opt if opt.eq(&config.channel_policy.0) => {
+                        match ChannelPolicy::from_str(value.trim()) {
+                            Ok(p) => config.channel_policy.1 = p,
+                            Err(e) => {
+                                return Err(anyhow!(
+                                    "Error: Could not parse `{}` for {}: {}",
+                                    value,
+                                    config.channel_policy.0,
+                                    e
+                                ))
+                            }
+                        }
+                    }
+                    opt if opt.eq(&config.peer_offline_timeout.0) => match value.parse::<u64>() {
+                        Ok(n) => config.peer_offline_timeout.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.peer_offline_timeout.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.peer_offline_blocks.0) => match value.parse::<u64>() {
+                        Ok(n) => config.peer_offline_blocks.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.peer_offline_blocks.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.keysend.0) => match value.parse::<bool>() {
+                        Ok(b) => config.keysend.1 = b,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a bool from `{}` for {}: {}",
+                                value,
+                                config.keysend.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.keysend_min_msat.0) => {
+                        config.keysend_min_msat.1 = parse_limit(value, &config.keysend_min_msat.0)?
+                    }
+                    opt if opt.eq(&config.keysend_max_msat.0) => {
+                        config.keysend_max_msat.1 = parse_limit(value, &config.keysend_max_msat.0)?
+                    }
+                    opt if opt.eq(&config.keysend_records.0) => {
+                        match parse_keysend_records(value) {
+                            Ok(r) => config.keysend_records.1 = r,
+                            Err(e) => {
+                                return Err(anyhow!(
+                                    "Error: Could not parse `{}` for {}: {}",
+                                    value,
+                                    config.keysend_records.0,
+                                    e
+                                ))
+                            }
+                        }
+                    }
+                    opt if opt.eq(&config.keysend_expiry.0) => match value.parse::<u64>() {
+                        Ok(n) => config.keysend_expiry.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.keysend_expiry.0,
+                                e
+                            ))
+                        }
+                    },
+                    opt if opt.eq(&config.forward_min_blocks.0) => match value.parse::<u32>() {
+                        Ok(n) => config.forward_min_blocks.1 = n,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Error: Could not parse a number from `{}` for {}: {}",
+                                value,
+                                config.forward_min_blocks.0,
+                                e
+                            ))
+                        }
+                    },
                     _ => (),
                 }
             }
         }
     }
 
+    if !config.webhooks.1.is_empty() && config.webhook_secret.1.is_none() {
+        return Err(anyhow!(
+            "Error: {} needs {} to sign the requests",
+            config.webhooks.0,
+            config.webhook_secret.0
+        ));
+    }
+
     Ok(())
 }
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use serde_json::json;
+
+use crate::{
+    forwards::ForwardHold, invoices::HodlInvoices, rpc::RpcClient, util::listpeerchannels,
+};
+
+/// Held htlcs summed up for one incoming channel, peer or all of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exposure {
+    pub held_msat: u64,
+    pub htlcs: u64,
+    pub nearest_cltv_expiry: Option<u32>,
+}
+impl Exposure {
+    pub fn add(&mut self, amount_msat: u64, cltv_expiry: u32) {
+        self.held_msat += amount_msat;
+        self.htlcs += 1;
+        self.nearest_cltv_expiry = Some(match self.nearest_cltv_expiry {
+            Some(c) => c.min(cltv_expiry),
+            None => cltv_expiry,
+        });
+    }
+
+    pub fn merge(&mut self, other: &Exposure) {
+        self.held_msat += other.held_msat;
+        self.htlcs += other.htlcs;
+        self.nearest_cltv_expiry = match (self.nearest_cltv_expiry, other.nearest_cltv_expiry) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub fn to_json(&self, blockheight: u32) -> serde_json::Value {
+        json!({
+            "held_msat": self.held_msat,
+            "htlcs": self.htlcs,
+            "nearest_cltv_expiry": self.nearest_cltv_expiry,
+            "blocks_to_expiry": self.nearest_cltv_expiry.map(|c| c.saturating_sub(blockheight)),
+        })
+    }
+}
+
+/// What lightningd tells us about an incoming channel.
+#[derive(Clone, Debug)]
+pub struct ChannelInfo {
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub max_accepted_htlcs: Option<u32>,
+}
+
+/// Everything we hold right now, for hodl-invoices and `forwards`, keyed
+/// by incoming short channel id.
+pub async fn held_by_channel(
+    states: &HodlInvoices,
+    forwards: &BTreeMap<String, ForwardHold>,
+) -> BTreeMap<String, Exposure> {
+    let mut channels: BTreeMap<String, Exposure> = BTreeMap::new();
+    for pay_hash in states.pay_hashes() {
+        if let Some(entry) = states.entry(&pay_hash).await {
+            for (key, htlc) in entry.htlcs() {
+                channels
+                    .entry(key.scid.clone())
+                    .or_default()
+                    .add(htlc.amount_msat, htlc.cltv_expiry);
+            }
+        }
+    }
+    for (key, htlc) in forwards.values().flat_map(|f| f.htlcs.iter()) {
+        channels
+            .entry(key.scid.clone())
+            .or_default()
+            .add(htlc.amount_msat, htlc.cltv_expiry);
+    }
+    channels
+}
+
+/// Our channels keyed by short channel id and by their aliases, since
+/// `htlc_accepted` may give us either.
+pub async fn channel_infos(rpc: &RpcClient) -> Result<BTreeMap<String, ChannelInfo>, Error> {
+    let mut infos = BTreeMap::new();
+    for channel in listpeerchannels(rpc).await?.channels {
+        let scid = match channel.short_channel_id {
+            Some(scid) => scid.to_string(),
+            None => continue,
+        };
+        let info = ChannelInfo {
+            short_channel_id: scid.clone(),
+            peer_id: channel.peer_id.to_string(),
+            max_accepted_htlcs: channel.max_accepted_htlcs,
+        };
+        if let Some(alias) = channel.alias {
+            for a in [alias.local, alias.remote].into_iter().flatten() {
+                infos.insert(a.to_string(), info.clone());
+            }
+        }
+        infos.insert(scid, info);
+    }
+    Ok(infos)
+}
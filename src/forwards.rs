@@ -0,0 +1,419 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering, time::Duration};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::{debug, info, warn};
+use serde_json::json;
+use tokio::time;
+
+use crate::{
+    blocks::cltv_timed_out,
+    invoices::HtlcKey,
+    limits::{peer_of, TEMPORARY_CHANNEL_FAILURE, TEMPORARY_NODE_FAILURE},
+    notifications,
+    payload::HtlcAccepted,
+    rpc::RpcClient,
+    shutdown::{forget_held, record_held},
+    state::{datastore_new_forward, datastore_update_forward, list_datastore_forwards, HodlState},
+    stats::Stats,
+    HodlUpdate, PluginState,
+};
+
+/// An incoming htlc held for a forward hold.
+#[derive(Clone, Copy, Debug)]
+pub struct HeldForward {
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    /// `hodl-release-all deadline` asked for it to be failed.
+    pub release_requested: bool,
+}
+
+/// A payment hash registered with `hodl-forward`. Htlcs for it that we
+/// would forward are held instead, `settled` lets them continue to the
+/// next hop and `canceled` fails them.
+#[derive(Clone, Debug)]
+pub struct ForwardHold {
+    pub update: HodlUpdate,
+    /// Incoming htlcs we hold.
+    pub htlcs: BTreeMap<HtlcKey, HeldForward>,
+}
+impl ForwardHold {
+    fn new(update: HodlUpdate) -> ForwardHold {
+        ForwardHold {
+            update,
+            htlcs: BTreeMap::new(),
+        }
+    }
+
+    /// Ask for every htlc expiring at or before `cltv_expiry` to be failed,
+    /// returns how many that are.
+    pub fn request_release(&mut self, cltv_expiry: u32) -> usize {
+        let mut count = 0;
+        for htlc in self.htlcs.values_mut() {
+            if htlc.cltv_expiry <= cltv_expiry {
+                htlc.release_requested = true;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+/// Give up on a held forward once the incoming htlc is as close to its
+/// timeout as a held hodl-invoice htlc may get, or once the outgoing htlc
+/// would leave the next hop fewer than `min_blocks`.
+pub fn forward_timed_out(
+    cltv_expiry: u32,
+    outgoing_cltv_value: Option<u32>,
+    blockheight: u32,
+    cltv_delta: u32,
+    min_blocks: u32,
+) -> bool {
+    cltv_timed_out(cltv_expiry, blockheight, cltv_delta)
+        || outgoing_cltv_value.is_some_and(|o| o <= blockheight + min_blocks)
+}
+
+pub async fn load_forwards(rpc: &RpcClient) -> Result<BTreeMap<String, ForwardHold>, Error> {
+    Ok(list_datastore_forwards(rpc)
+        .await?
+        .into_iter()
+        .map(|(pay_hash, update)| (pay_hash, ForwardHold::new(update)))
+        .collect())
+}
+
+/// Pick up states written by `hodl-forward` or directly to the datastore.
+pub async fn refresh_forwards(plugin: &Plugin<PluginState>) {
+    let updates = match list_datastore_forwards(&plugin.state().rpc).await {
+        Ok(u) => u,
+        Err(e) => {
+            warn!("Error getting forward hold states: {}", e);
+            return;
+        }
+    };
+    let mut changes = Vec::new();
+    {
+        let mut forwards = plugin.state().forwards.lock();
+        forwards.retain(|pay_hash, _| updates.contains_key(pay_hash));
+        for (pay_hash, update) in updates {
+            match forwards.get_mut(&pay_hash) {
+                Some(hold) if update.generation > hold.update.generation => {
+                    if hold.update.state != update.state {
+                        changes.push((pay_hash, Some(hold.update.state), update.state));
+                    }
+                    hold.update = update;
+                }
+                Some(_) => (),
+                None => {
+                    changes.push((pay_hash.clone(), None, update.state));
+                    forwards.insert(pay_hash, ForwardHold::new(update));
+                }
+            }
+        }
+    }
+    for (pay_hash, old, new) in changes {
+        info!(
+            "payment_hash: `{}`. Forward hold state changed: {} -> {}",
+            pay_hash,
+            old.map(|s| s.to_string().to_uppercase())
+                .unwrap_or_else(|| "NONE".to_string()),
+            new.to_string().to_uppercase()
+        );
+        notifications::state_changed(plugin, &pay_hash, old, new).await;
+    }
+}
+
+/// Register `pay_hash` for holding (`hold`), or release its htlcs with
+/// `continue` (`settled`) or `fail` (`canceled`).
+pub async fn set_forward(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    action: &str,
+) -> Result<HodlState, Error> {
+    if pay_hash.len() != 64 || !pay_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("payment_hash must be 64 hex characters"));
+    }
+    let pay_hash = pay_hash.to_lowercase();
+    let rpc = &plugin.state().rpc;
+    let _write = plugin.state().shutdown.write();
+    let (old, update) = if action == "hold" {
+        let response = datastore_new_forward(rpc, pay_hash.clone(), HodlState::Open.to_string())
+            .await
+            .map_err(|e| anyhow!("payment_hash: `{}` is already held? {}", pay_hash, e))?;
+        let update = HodlUpdate {
+            state: HodlState::Open,
+            generation: response.generation.unwrap_or(0),
+        };
+        plugin
+            .state()
+            .forwards
+            .lock()
+            .insert(pay_hash.clone(), ForwardHold::new(update));
+        (None, update)
+    } else {
+        let new_state = match action {
+            "continue" => HodlState::Settled,
+            "fail" => HodlState::Canceled,
+            _ => return Err(anyhow!("action must be hold, continue or fail")),
+        };
+        let current = plugin
+            .state()
+            .forwards
+            .lock()
+            .get(&pay_hash)
+            .map(|h| h.update)
+            .ok_or_else(|| anyhow!("payment_hash: `{}` is not a forward hold", pay_hash))?;
+        if !current.state.is_valid_transition(&new_state) {
+            return Err(anyhow!(
+                "payment_hash: `{}`. Can't go from {} to {}",
+                pay_hash,
+                current.state,
+                new_state
+            ));
+        }
+        let response = datastore_update_forward(
+            rpc,
+            pay_hash.clone(),
+            new_state.to_string(),
+            current.generation,
+        )
+        .await?;
+        let update = HodlUpdate {
+            state: new_state,
+            generation: response.generation.unwrap_or(current.generation + 1),
+        };
+        if let Some(hold) = plugin.state().forwards.lock().get_mut(&pay_hash) {
+            hold.update = update;
+        }
+        (Some(current.state), update)
+    };
+    info!(
+        "payment_hash: `{}`. Forward hold State={}",
+        pay_hash,
+        update.state.to_string().to_uppercase()
+    );
+    notifications::state_changed(plugin, &pay_hash, old, update.state).await;
+    Ok(update.state)
+}
+
+/// Move a forward hold we hold htlcs for to `new_state`. On a conflict the
+/// caller just looks again after the next refresh.
+async fn update_forward(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    current: HodlUpdate,
+    new_state: HodlState,
+) {
+    let _write = plugin.state().shutdown.write();
+    match datastore_update_forward(
+        &plugin.state().rpc,
+        pay_hash.to_string(),
+        new_state.to_string(),
+        current.generation,
+    )
+    .await
+    {
+        Ok(o) => {
+            if let Some(hold) = plugin.state().forwards.lock().get_mut(pay_hash) {
+                hold.update = HodlUpdate {
+                    state: new_state,
+                    generation: o.generation.unwrap_or(current.generation + 1),
+                };
+            }
+            notifications::state_changed(plugin, pay_hash, Some(current.state), new_state).await;
+        }
+        Err(e) => debug!(
+            "payment_hash: `{}`. Could not update forward hold: {}",
+            pay_hash, e
+        ),
+    }
+}
+
+/// Hold an htlc we would forward if its payment hash was registered with
+/// `hodl-forward`. Returns the hook result once it is released, `None` if
+/// the htlc is none of our business.
+pub async fn hold_forward(
+    plugin: &Plugin<PluginState>,
+    payload: &HtlcAccepted,
+) -> Option<serde_json::Value> {
+    // we are the last hop, this is for a hodl-invoice if anything
+    if payload.onion.short_channel_id.is_none() && payload.onion.next_onion.is_none() {
+        return None;
+    }
+    let pay_hash = payload.htlc.payment_hash.as_str();
+    let scid = payload.htlc.short_channel_id.as_str();
+    let htlc_id = payload.htlc.id;
+    let cltv_expiry = payload.htlc.cltv_expiry;
+    let amount_msat = payload.htlc.amount_msat;
+    let htlc_key = HtlcKey {
+        scid: scid.to_string(),
+        id: htlc_id,
+    };
+    let state = plugin
+        .state()
+        .forwards
+        .lock()
+        .get(pay_hash)
+        .map(|h| h.update.state)?;
+    if state != HodlState::Settled && plugin.state().maintenance.load(Ordering::Relaxed) {
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Maintenance mode is on. Rejecting forward...",
+            pay_hash, scid, htlc_id
+        );
+        Stats::inc(&plugin.state().stats.maintenance_rejections);
+        return Some(json!({
+            "result": "fail",
+            "failure_message": TEMPORARY_NODE_FAILURE,
+        }));
+    }
+    if state != HodlState::Settled {
+        let limits = plugin.state().config.lock().limits();
+        let peer_id = if limits.needs_peers() {
+            peer_of(plugin, scid).await
+        } else {
+            None
+        };
+        let reserved =
+            plugin
+                .state()
+                .held
+                .lock()
+                .reserve(&limits, &htlc_key, peer_id.as_deref(), amount_msat);
+        if let Err(limit) = reserved {
+            warn!(
+                "payment_hash: `{}` scid: `{}` htlc: `{}`. Holding forward of {}msat would exceed {} limit. Rejecting forward...",
+                pay_hash, scid, htlc_id, amount_msat, limit
+            );
+            Stats::inc(&plugin.state().stats.limit_rejections);
+            plugin
+                .state()
+                .metrics
+                .limit_rejections
+                .with_label_values(&[limit])
+                .inc();
+            return Some(json!({
+                "result": "fail",
+                "failure_message": TEMPORARY_CHANNEL_FAILURE,
+            }));
+        }
+    }
+    if let Some(hold) = plugin.state().forwards.lock().get_mut(pay_hash) {
+        hold.htlcs.insert(
+            htlc_key.clone(),
+            HeldForward {
+                amount_msat,
+                cltv_expiry,
+                release_requested: false,
+            },
+        );
+    }
+    info!(
+        "payment_hash: `{}` scid: `{}` htlc_id: `{}`. Holding forward of {}msat",
+        pay_hash, scid, htlc_id, amount_msat
+    );
+    record_held(plugin, pay_hash, &htlc_key, amount_msat, cltv_expiry).await;
+
+    let result = loop {
+        let (current, held, release_requested) = match plugin.state().forwards.lock().get(pay_hash)
+        {
+            Some(hold) => (
+                hold.update,
+                hold.htlcs.len(),
+                hold.htlcs
+                    .get(&htlc_key)
+                    .is_some_and(|h| h.release_requested),
+            ),
+            None => {
+                warn!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Forward hold was removed. Rejecting forward...",
+                    pay_hash, scid, htlc_id
+                );
+                break json!({"result": "fail"});
+            }
+        };
+        let (cltv_delta, min_blocks) = {
+            let config = plugin.state().config.lock();
+            (config.cltv_delta.1 as u32, config.forward_min_blocks.1)
+        };
+        let timed_out = forward_timed_out(
+            cltv_expiry,
+            payload.onion.outgoing_cltv_value,
+            *plugin.state().blockheight.lock(),
+            cltv_delta,
+            min_blocks,
+        );
+        match current.state {
+            HodlState::Settled => {
+                info!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Releasing held forward. State=SETTLED",
+                    pay_hash, scid, htlc_id
+                );
+                break json!({"result": "continue"});
+            }
+            HodlState::Canceled => {
+                info!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Failing held forward. State=CANCELED",
+                    pay_hash, scid, htlc_id
+                );
+                break json!({"result": "fail"});
+            }
+            _ if release_requested => {
+                warn!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Release of held forward requested. Rejecting forward...",
+                    pay_hash, scid, htlc_id
+                );
+                break json!({"result": "fail"});
+            }
+            _ if timed_out => {
+                warn!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Held forward timed out. Rejecting forward...",
+                    pay_hash, scid, htlc_id
+                );
+                if current.state == HodlState::Accepted && held == 1 {
+                    update_forward(plugin, pay_hash, current, HodlState::Open).await;
+                }
+                break json!({"result": "fail"});
+            }
+            HodlState::Open => update_forward(plugin, pay_hash, current, HodlState::Accepted).await,
+            HodlState::Accepted => (),
+        }
+        time::sleep(Duration::from_secs(3)).await;
+    };
+    if let Some(hold) = plugin.state().forwards.lock().get_mut(pay_hash) {
+        hold.htlcs.remove(&htlc_key);
+    }
+    plugin.state().held.lock().release(&htlc_key);
+    forget_held(plugin, &htlc_key).await;
+    Some(result)
+}
+
+/// What `hodl-forward` answers: the registered hashes, their state and
+/// how many htlcs we hold for them.
+pub fn forwards_json(plugin: &Plugin<PluginState>) -> serde_json::Value {
+    let forwards = plugin.state().forwards.lock();
+    json!({
+        "forwards": forwards
+            .iter()
+            .map(|(pay_hash, hold)| json!({
+                "payment_hash": pay_hash,
+                "state": hold.update.state.to_string(),
+                "htlcs": hold.htlcs.len(),
+                "cltv_expiry": hold.htlcs.values().map(|h| h.cltv_expiry).min(),
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwards_time_out_on_either_side() {
+        // incoming: 800_200 <= 800_154 + 40 + 6
+        assert!(forward_timed_out(800_200, None, 800_154, 40, 18));
+        assert!(!forward_timed_out(800_200, None, 800_153, 40, 18));
+        // outgoing: the next hop needs at least 18 blocks
+        assert!(forward_timed_out(800_500, Some(800_118), 800_100, 40, 18));
+        assert!(!forward_timed_out(800_500, Some(800_119), 800_100, 40, 18));
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use cln_rpc::model::ListdatastoreDatastore;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::{
+    state::{
+        del_datastore_raw, list_datastore_raw, HodlState, HODLVOICE_DATASTORE_HTLC_EXPIRY,
+        HODLVOICE_DATASTORE_ONION, HODLVOICE_DATASTORE_PAID, HODLVOICE_DATASTORE_REASON,
+        HODLVOICE_PLUGIN_NAME, HODLVOICE_STATE_NAME,
+    },
+    stats::Stats,
+    util::listinvoices,
+    PluginState,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GarbageReason {
+    /// lightningd has no invoice for this payment hash.
+    Orphaned,
+    /// Not a payment hash at all.
+    BadPaymentHash,
+    /// Only half written, there is no `state` key.
+    MissingState,
+    BadState,
+    BadExpiry,
+    /// Keys we never write.
+    UnknownKey,
+}
+impl GarbageReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GarbageReason::Orphaned => "orphaned",
+            GarbageReason::BadPaymentHash => "bad_payment_hash",
+            GarbageReason::MissingState => "missing_state",
+            GarbageReason::BadState => "bad_state",
+            GarbageReason::BadExpiry => "bad_expiry",
+            GarbageReason::UnknownKey => "unknown_key",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Garbage {
+    pub pay_hash: String,
+    pub reason: GarbageReason,
+    pub keys: Vec<Vec<String>>,
+}
+impl Garbage {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "payment_hash": self.pay_hash,
+            "reason": self.reason.as_str(),
+            "keys": self.keys,
+        })
+    }
+}
+
+/// Walk `hodlvoice/*` and `hodlvoice-state/*` and return the entries
+/// that are orphaned or malformed. Invoices with held htlcs are never reported, and an entry is
+/// only orphaned if lightningd positively says it doesn't know the invoice.
+pub async fn find_garbage(plugin: &Plugin<PluginState>) -> Result<Vec<Garbage>, Error> {
+    let rpc = &plugin.state().rpc;
+    let mut garbage = Vec::new();
+    let mut states: BTreeMap<String, ListdatastoreDatastore> =
+        list_datastore_raw(rpc, Some(vec![HODLVOICE_STATE_NAME.to_string()]))
+            .await?
+            .datastore
+            .into_iter()
+            .filter_map(|d| d.key.get(1).cloned().map(|h| (h, d)))
+            .collect();
+    // payment hashes with a `hodlvoice/<payment_hash>` entry, then the
+    // ones that only have a state
+    let mut top = list_datastore_raw(rpc, Some(vec![HODLVOICE_PLUGIN_NAME.to_string()]))
+        .await?
+        .datastore;
+    for pay_hash in states.keys() {
+        if !top.iter().any(|d| d.key.get(1) == Some(pay_hash)) {
+            top.push(ListdatastoreDatastore {
+                key: vec![HODLVOICE_PLUGIN_NAME.to_string(), pay_hash.clone()],
+                generation: None,
+                hex: None,
+                string: None,
+            });
+        }
+    }
+    for data in top {
+        let pay_hash = match data.key.get(1) {
+            Some(h) => h.clone(),
+            None => continue,
+        };
+        if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+            if !entry.htlcs().is_empty() {
+                continue;
+            }
+        }
+        let state = states.remove(&pay_hash);
+        if data.string.is_some() || data.hex.is_some() {
+            garbage.push(Garbage {
+                pay_hash,
+                reason: GarbageReason::UnknownKey,
+                keys: vec![data.key],
+            });
+            continue;
+        }
+        let children = list_datastore_raw(
+            rpc,
+            Some(vec![HODLVOICE_PLUGIN_NAME.to_string(), pay_hash.clone()]),
+        )
+        .await?
+        .datastore;
+        let leaf = |name: &str| {
+            children
+                .iter()
+                .find(|c| c.key.len() == 3 && c.key[2] == name)
+        };
+        let mut keys: Vec<Vec<String>> = children.iter().map(|c| c.key.clone()).collect();
+        if let Some(s) = state.as_ref() {
+            keys.push(s.key.clone());
+        }
+        if let Some(onion) = leaf(HODLVOICE_DATASTORE_ONION) {
+            // only the htlcs below it can be deleted
+            keys.retain(|k| k != &onion.key);
+            keys.extend(
+                list_datastore_raw(rpc, Some(onion.key.clone()))
+                    .await?
+                    .datastore
+                    .into_iter()
+                    .map(|d| d.key),
+            );
+        }
+        let unknown: Vec<Vec<String>> = children
+            .iter()
+            .filter(|c| {
+                c.key.len() != 3
+                    || (c.key[2] != HODLVOICE_DATASTORE_HTLC_EXPIRY
+                        && c.key[2] != HODLVOICE_DATASTORE_PAID
+                        && c.key[2] != HODLVOICE_DATASTORE_REASON
+                        && c.key[2] != HODLVOICE_DATASTORE_ONION)
+            })
+            .map(|c| c.key.clone())
+            .collect();
+
+        let reason = if pay_hash.len() != 64 || !pay_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(GarbageReason::BadPaymentHash)
+        } else if state.is_none() {
+            Some(GarbageReason::MissingState)
+        } else if !matches!(
+            state
+                .as_ref()
+                .and_then(|s| s.string.as_deref())
+                .map(HodlState::from_str),
+            Some(Ok(_))
+        ) {
+            Some(GarbageReason::BadState)
+        } else if leaf(HODLVOICE_DATASTORE_HTLC_EXPIRY).is_some()
+            && !matches!(
+                leaf(HODLVOICE_DATASTORE_HTLC_EXPIRY)
+                    .and_then(|e| e.string.as_deref())
+                    .map(|e| e.parse::<u32>()),
+                Some(Ok(_))
+            )
+        {
+            Some(GarbageReason::BadExpiry)
+        } else {
+            match listinvoices(rpc, None, Some(pay_hash.clone()), None).await {
+                Ok(i) if i.invoices.is_empty() => Some(GarbageReason::Orphaned),
+                Ok(_i) => None,
+                Err(e) => {
+                    warn!(
+                        "payment_hash: `{}`. Could not check invoice: {}",
+                        pay_hash, e
+                    );
+                    None
+                }
+            }
+        };
+        match reason {
+            Some(reason) => garbage.push(Garbage {
+                pay_hash,
+                reason,
+                keys,
+            }),
+            None if !unknown.is_empty() => garbage.push(Garbage {
+                pay_hash,
+                reason: GarbageReason::UnknownKey,
+                keys: unknown,
+            }),
+            None => (),
+        }
+    }
+    Ok(garbage)
+}
+
+/// Find garbage and, unless `dry_run`, delete it.
+pub async fn collect_garbage(
+    plugin: &Plugin<PluginState>,
+    dry_run: bool,
+) -> Result<(Vec<Garbage>, u64), Error> {
+    let stats = &plugin.state().stats;
+    let garbage = find_garbage(plugin).await?;
+    Stats::inc(&stats.gc_runs);
+    stats.gc_orphaned.store(
+        garbage
+            .iter()
+            .filter(|g| g.reason == GarbageReason::Orphaned)
+            .count() as u64,
+        Ordering::Relaxed,
+    );
+    stats.gc_malformed.store(
+        garbage
+            .iter()
+            .filter(|g| g.reason != GarbageReason::Orphaned)
+            .count() as u64,
+        Ordering::Relaxed,
+    );
+    let mut deleted = 0;
+    if dry_run {
+        return Ok((garbage, deleted));
+    }
+    let rpc = &plugin.state().rpc;
+    for g in garbage.iter() {
+        info!(
+            "payment_hash: `{}`. Deleting {} datastore entry",
+            g.pay_hash,
+            g.reason.as_str()
+        );
+        for key in g.keys.iter() {
+            match del_datastore_raw(rpc, key.clone()).await {
+                Ok(_o) => {
+                    deleted += 1;
+                    Stats::inc(&stats.gc_deleted);
+                }
+                Err(e) => warn!("Could not delete {:?}: {}", key, e),
+            }
+        }
+        if g.reason != GarbageReason::UnknownKey {
+            plugin.state().states.retain(|hash| hash != &g.pay_hash);
+        }
+    }
+    Ok((garbage, deleted))
+}
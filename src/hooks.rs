@@ -1,378 +1,841 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Error};
 use cln_plugin::Plugin;
 use cln_rpc::primitives::Amount;
 use log::{debug, info, warn};
+use rand::Rng;
 use serde_json::json;
 use tokio::time;
 
 use crate::{
+    blocks::cltv_timed_out,
+    forwards::hold_forward,
+    invoices::{HodlInvoiceEntry, HodlPaid, HtlcKey},
+    keysend::{is_keysend_label, keysend_to_hold, register_keysend},
+    limits::{peer_of, TEMPORARY_CHANNEL_FAILURE, TEMPORARY_NODE_FAILURE},
+    notifications::{self, HtlcResult},
+    offers::register_offer_invoice,
+    payload::{HtlcAccepted, HtlcOnion, Onion},
+    shutdown::{forget_held, record_held},
+    state::{
+        datastore_htlc_expiry, datastore_onion, datastore_paid, datastore_reason,
+        datastore_update_state, del_datastore_reason, list_datastore_reason, list_datastore_state,
+        DatastoreError, HodlReason, HodlState,
+    },
+    stats::Stats,
+    util::{decodepay, listinvoices},
     HodlUpdate, PluginState,
-    state::{datastore_htlc_expiry, datastore_update_state, list_datastore_state, HodlState},
-    util::{listinvoices, make_rpc_path},
 };
 
+const MAX_STATE_UPDATE_ATTEMPTS: u32 = 10;
+
 pub(crate) async fn htlc_handler(
     plugin: Plugin<PluginState>,
     v: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    if let Some(htlc) = v.get("htlc") {
-        if let Some(pay_hash) = htlc
-            .get("payment_hash")
-            .and_then(|pay_hash| pay_hash.as_str())
-        {
-            debug!("payment_hash: `{}`. htlc_hook started!", pay_hash);
-            let rpc_path = make_rpc_path(&plugin);
+    let payload = match serde_json::from_value::<HtlcAccepted>(v.clone()) {
+        Ok(p) => p,
+        Err(e) => return Ok(malformed_payload(&plugin, &v, e).await),
+    };
+    let pay_hash = payload.htlc.payment_hash.as_str();
+    let scid = payload.htlc.short_channel_id.as_str();
+    let htlc_id = payload.htlc.id;
+    let amount_msat = payload.htlc.amount_msat;
+    let cltv_expiry = payload.htlc.cltv_expiry;
+    debug!("payment_hash: `{}`. htlc_hook started!", pay_hash);
+    let rpc = &plugin.state().rpc;
 
-            let invoice;
-            let cltv_delta;
-            let cltv_expiry = match htlc.get("cltv_expiry") {
-                Some(ce) => ce.as_u64().unwrap() as u32,
-                None => {
+    if let Some(result) = hold_forward(&plugin, &payload).await {
+        return Ok(result);
+    }
+    let htlc_key = HtlcKey {
+        scid: scid.to_string(),
+        id: htlc_id,
+    };
+    if plugin.state().states.get(pay_hash).is_none() {
+        match keysend_to_hold(&plugin, &payload) {
+            Ok(Some(keysend)) => {
+                // a rejected keysend must not leave an invoice with the
+                // sender's preimage behind, so check before creating it
+                if let Some(rejection) =
+                    reject_hold(&plugin, pay_hash, &htlc_key, amount_msat).await
+                {
+                    return Ok(rejection);
+                }
+                if let Err(e) = register_keysend(&plugin, pay_hash, keysend).await {
+                    plugin.state().held.lock().release(&htlc_key);
                     warn!(
-                        "payment_hash: `{}`. cltv_expiry not found! Rejecting htlc...",
-                        pay_hash
+                        "payment_hash: `{}`. Not holding keysend payment: {}",
+                        pay_hash, e
                     );
-                    return Ok(json!({"result": "fail"}));
                 }
-            };
-            let amount_msat;
-            let scid;
-            let htlc_id;
-            let HodlState;
-            {
-                let mut states = plugin.state().states.lock().await;
-                match states.get(&pay_hash.to_string()) {
-                    Some(h) => {
-                        debug!(
-                            "payment_hash: `{}`. Htlc is for a known hodl-invoice! Processing...",
-                            pay_hash
-                        );
-                        HodlState = h.state;
-                        invoice = plugin
-                            .state()
-                            .invoices
-                            .lock()
-                            .get(&pay_hash.to_string())
-                            .unwrap()
-                            .clone();
-                    }
-                    None => {
-                        debug!(
-                            "payment_hash: `{}`. Htlc for fresh invoice arrived. Checking if it's a hodl-invoice...",
-                            pay_hash
-                        );
-
-                        match list_datastore_state(&rpc_path, pay_hash.to_string()).await {
-                            Ok(s) => {
-                                debug!(
-                                    "payment_hash: `{}`. Htlc is indeed for a hodl-invoice! Processing...",
-                                    pay_hash
-                                );
-                                HodlState = HodlState::from_str(&s.string.unwrap())?;
-                                let gen = if let Some(g) = s.generation { g } else { 0 };
+            }
+            Ok(None) => (),
+            Err(e) => warn!(
+                "payment_hash: `{}`. Not holding keysend payment: {}",
+                pay_hash, e
+            ),
+        }
+        if let Err(e) = register_offer_invoice(&plugin, &payload).await {
+            warn!(
+                "payment_hash: `{}`. Could not check for a held offer: {}",
+                pay_hash, e
+            );
+        }
+    }
 
-                                datastore_htlc_expiry(
-                                    &rpc_path,
-                                    pay_hash.to_string(),
-                                    cltv_expiry.to_string(),
-                                )
-                                .await?;
+    let HodlState = match plugin
+        .state()
+        .states
+        .get_or_load(pay_hash, || async {
+            debug!(
+                "payment_hash: `{}`. Htlc for fresh invoice arrived. Checking if it's a hodl-invoice...",
+                pay_hash
+            );
+            let s = match list_datastore_state(rpc, pay_hash.to_string()).await {
+                Ok(s) => s,
+                Err(_e) => return Ok(None),
+            };
+            debug!(
+                "payment_hash: `{}`. Htlc is indeed for a hodl-invoice! Processing...",
+                pay_hash
+            );
+            let HodlState = HodlState::from_str(&s.string.unwrap_or_default())?;
+            let gen = if let Some(g) = s.generation { g } else { 0 };
 
-                                invoice = listinvoices(&rpc_path, None, Some(pay_hash.to_string()))
-                                    .await?
-                                    .invoices
-                                    .first()
-                                    .ok_or(anyhow!(
-                                        "payment_hash: `{}`. Hodl-invoice not found!",
-                                        pay_hash
-                                    ))?
-                                    .clone();
-                                plugin
-                                    .state()
-                                    .invoices
-                                    .lock()
-                                    .insert(pay_hash.to_string(), invoice.clone());
+            datastore_htlc_expiry(rpc, pay_hash.to_string(), cltv_expiry.to_string())
+                .await?;
 
-                                states.insert(
-                                    pay_hash.to_string(),
-                                    HodlUpdate {
-                                        state: HodlState,
-                                        generation: gen,
-                                    },
-                                );
-                            }
-                            Err(_e) => {
-                                debug!(
-                                    "payment_hash: `{}`. Not a hodl-invoice! Continue...",
-                                    pay_hash
-                                );
-                                return Ok(json!({"result": "continue"}));
-                            }
-                        };
-                    }
-                }
-            }
-            debug!("payment_hash: `{}`. Init lock dropped", pay_hash);
-            match HodlState {
-                HodlState::Canceled => {
-                    info!(
-                        "payment_hash: `{}`. Htlc arrived after hodl-cancellation was requested. Rejecting htlc...",
-                        pay_hash
-                    );
-                    return Ok(json!({"result": "fail"}));
-                }
-                _ => (),
-            }
-            htlc_id = match htlc.get("id") {
-                Some(ce) => ce.as_u64().unwrap(),
-                None => {
-                    warn!(
-                        "payment_hash: `{}`. htlc id not found! Rejecting htlc...",
-                        pay_hash
-                    );
-                    return Ok(json!({"result": "fail"}));
-                }
-            };
-            scid = match htlc.get("short_channel_id") {
-                Some(ce) => ce.as_str().unwrap(),
-                None => {
-                    warn!(
-                        "payment_hash: `{}`. short_channel_id not found! Rejecting htlc...",
-                        pay_hash
-                    );
-                    return Ok(json!({"result": "fail"}));
-                }
-            };
-            cltv_delta = plugin.state().config.lock().clone().cltv_delta.1 as u32;
+            let invoice = listinvoices(rpc, None, Some(pay_hash.to_string()), None)
+                .await?
+                .invoices
+                .first()
+                .ok_or(anyhow!(
+                    "payment_hash: `{}`. Hodl-invoice not found!",
+                    pay_hash
+                ))?
+                .clone();
 
-            amount_msat = match htlc.get("amount_msat") {
-                Some(ce) =>
-                // Amount::msat(&serde_json::from_str::<Amount>(ce).unwrap()), bugging trailing characters error
-                {
-                    let amt_str = ce.as_str().unwrap();
-                    amt_str[..amt_str.len() - 4].parse::<u64>().unwrap()
-                }
-                None => {
-                    warn!(
-                        "payment_hash: `{}` scid: `{}` htlc_id: {}: amount_msat not found! Rejecting htlc...",
-                        pay_hash, scid, htlc_id
-                    );
-                    return Ok(json!({"result": "fail"}));
-                }
-            };
-            {
-                let mut invoice_amts = plugin.state().invoice_amts.lock();
-                if let Some(amt) = invoice_amts.get_mut(&pay_hash.to_string()) {
-                    *amt += amount_msat;
-                } else {
-                    invoice_amts.insert(pay_hash.to_string(), amount_msat);
+            let mut entry = HodlInvoiceEntry::new(
+                HodlUpdate {
+                    state: HodlState,
+                    generation: gen,
+                },
+                invoice,
+            )?;
+            entry.reason = list_datastore_reason(rpc, pay_hash.to_string())
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("payment_hash: `{}`. Unreadable reason: {}", pay_hash, e);
+                    None
+                });
+            let keysend = is_keysend_label(&entry.invoice.label);
+            if let Some(bolt11) = entry.invoice.bolt11.clone().filter(|_b| !keysend) {
+                match decodepay(rpc, bolt11).await {
+                    Ok(d) => entry.payment_secret = d.payment_secret.map(|s| s.to_string()),
+                    Err(e) => warn!(
+                        "payment_hash: `{}`. Could not read payment_secret, not checking it: {}",
+                        pay_hash, e
+                    ),
                 }
             }
+            Ok(Some(entry))
+        })
+        .await?
+    {
+        Some(h) => h.state,
+        None => {
+            debug!(
+                "payment_hash: `{}`. Not a hodl-invoice! Continue...",
+                pay_hash
+            );
+            return Ok(json!({"result": "continue"}));
+        }
+    };
+    match HodlState {
+        HodlState::Canceled => {
             info!(
-                "payment_hash: `{}` scid: `{}` htlc_id: `{}`. Holding {}msat",
-                pay_hash,
-                scid.to_string(),
-                htlc_id,
-                amount_msat
+                "payment_hash: `{}`. Htlc arrived after hodl-cancellation was requested. Rejecting htlc...",
+                pay_hash
+            );
+            return Ok(json!({"result": "fail"}));
+        }
+        _ => (),
+    }
+    let cltv_delta = plugin.state().config.lock().cltv_delta.1 as u32;
+    if HodlState != HodlState::Settled {
+        if let Some(rejection) = reject_hold(&plugin, pay_hash, &htlc_key, amount_msat).await {
+            return Ok(rejection);
+        }
+    }
+    match plugin
+        .state()
+        .states
+        .with_entry(pay_hash, |e| {
+            e.check_part(
+                payload.onion.payment_secret.as_deref(),
+                payload.onion.total_msat,
+            )
+            .map_err(|err| {
+                (
+                    Some(incorrect_or_unknown_payment_details(
+                        amount_msat,
+                        *plugin.state().blockheight.lock(),
+                    )),
+                    err,
+                )
+            })?;
+            e.hold_htlc(htlc_key.clone(), amount_msat, cltv_expiry)
+                .map_err(|err| (None, err))
+        })
+        .await
+    {
+        Some(Ok(())) => (),
+        Some(Err((Some(failure_message), e))) => {
+            plugin.state().held.lock().release(&htlc_key);
+            warn!(
+                "scid: `{}` htlc: `{}`. {}. Rejecting htlc...",
+                scid, htlc_id, e
             );
+            return Ok(json!({
+                "result": "fail",
+                "failure_message": failure_message,
+            }));
+        }
+        Some(Err((None, e))) => {
+            plugin.state().held.lock().release(&htlc_key);
+            warn!("{}. Rejecting htlc...", e);
+            return Ok(json!({"result": "fail"}));
+        }
+        None => {
+            plugin.state().held.lock().release(&htlc_key);
+            warn!(
+                "payment_hash: `{}` scid: `{}` htlc: `{}`. DROPPED INVOICE from internal state!",
+                pay_hash, scid, htlc_id
+            );
+            return Ok(json!({"result": "fail"}));
+        }
+    }
+    info!(
+        "payment_hash: `{}` scid: `{}` htlc_id: `{}`. Holding {}msat",
+        pay_hash,
+        scid.to_string(),
+        htlc_id,
+        amount_msat
+    );
+    store_onion(&plugin, pay_hash, &htlc_key, amount_msat, &payload.onion).await;
+    record_held(&plugin, pay_hash, &htlc_key, amount_msat, cltv_expiry).await;
+    notifications::htlc_held(&plugin, pay_hash, &htlc_key, amount_msat).await;
 
-            loop {
-                {
-                    let states = plugin.state().states.lock().await.clone();
-                    match states.get(&pay_hash.to_string()) {
-                        Some(datastore) => {
-                            let HodlState = datastore.state;
-                            let generation = datastore.generation;
-                            let now = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs();
+    let mut attempts = 0;
+    loop {
+        {
+            match plugin.state().states.entry(pay_hash).await {
+                Some(entry) => {
+                    let HodlState = entry.state;
+                    let generation = entry.generation;
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs();
 
-                            if invoice.expires_at <= now + 60
-                                && HodlState.is_valid_transition(&HodlState::Canceled)
-                            {
-                                warn!(
-                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Hodl-invoice expired! State=CANCELED",
-                                    pay_hash, scid, htlc_id
-                                );
-                                match datastore_update_state(
-                                    &rpc_path,
-                                    pay_hash.to_string(),
-                                    HodlState::Canceled.to_string(),
-                                    generation,
-                                )
-                                .await
-                                {
-                                    Ok(_o) => (),
-                                    Err(_e) => {
-                                        time::sleep(Duration::from_secs(2)).await;
-                                        continue;
-                                    }
-                                };
-                                *plugin
-                                    .state()
-                                    .invoice_amts
-                                    .lock()
-                                    .get_mut(&pay_hash.to_string())
-                                    .unwrap() -= amount_msat;
-                                return Ok(json!({"result": "fail"}));
-                            }
+                    if entry.invoice.expires_at <= now + 60
+                        && HodlState.is_valid_transition(&HodlState::Canceled)
+                    {
+                        warn!(
+                            "payment_hash: `{}` scid: `{}` htlc: `{}`. Hodl-invoice expired! State=CANCELED Reason=EXPIRED",
+                            pay_hash, scid, htlc_id
+                        );
+                        // the reason goes first, whoever sees the
+                        // new state must be able to find it
+                        if entry.reason != Some(HodlReason::Expired) {
+                            set_reason(&plugin, pay_hash, Some(HodlReason::Expired)).await;
+                        }
+                        match update_state(
+                            &plugin,
+                            pay_hash,
+                            HodlState::Canceled,
+                            generation,
+                            &mut attempts,
+                        )
+                        .await
+                        {
+                            StateUpdate::Done => Stats::inc(&plugin.state().stats.auto_cancels),
+                            StateUpdate::GiveUp => (),
+                            StateUpdate::Retry => continue,
+                        };
+                        release_htlc(&plugin, pay_hash, &htlc_key, HtlcResult::Failed).await;
+                        return Ok(json!({"result": "fail"}));
+                    }
 
-                            if cltv_expiry
-                                <= plugin.state().blockheight.lock().clone() + cltv_delta + 6
-                                && HodlState.is_valid_transition(&HodlState::Open)
+                    let timed_out =
+                        cltv_timed_out(cltv_expiry, *plugin.state().blockheight.lock(), cltv_delta);
+                    let release_requested = entry
+                        .htlcs()
+                        .get(&htlc_key)
+                        .is_some_and(|h| h.release_requested);
+                    if (timed_out || release_requested)
+                        && HodlState.is_valid_transition(&HodlState::Open)
+                    {
+                        if timed_out {
+                            warn!(
+                                "payment_hash: `{}` scid: `{}` htlc: `{}`. HTLC timed out. Rejecting htlc...",
+                                pay_hash, scid, htlc_id
+                            );
+                            Stats::inc(&plugin.state().stats.cltv_timeouts);
+                        } else {
+                            warn!(
+                                "payment_hash: `{}` scid: `{}` htlc: `{}`. Release requested. Rejecting htlc...",
+                                pay_hash, scid, htlc_id
+                            );
+                        }
+                        if entry.amount_msat() > entry.held_msat() - amount_msat
+                            && HodlState == HodlState::Accepted
+                        {
+                            // the payer may still retry, so it is open
+                            // again. Like for expiry, the reason goes first.
+                            if timed_out && entry.reason != Some(HodlReason::CltvTimeout) {
+                                set_reason(&plugin, pay_hash, Some(HodlReason::CltvTimeout)).await;
+                            }
+                            match update_state(
+                                &plugin,
+                                pay_hash,
+                                HodlState::Open,
+                                generation,
+                                &mut attempts,
+                            )
+                            .await
                             {
-                                warn!(
-                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. HTLC timed out. Rejecting htlc...",
-                                    pay_hash, scid, htlc_id
-                                );
-                                let invoice_amts = plugin.state().invoice_amts.lock().clone();
-                                let cur_amt = invoice_amts.get(&pay_hash.to_string()).unwrap();
-                                if Amount::msat(&invoice.amount_msat.unwrap())
-                                    > cur_amt - amount_msat
-                                    && HodlState == HodlState::Accepted
-                                {
-                                    match datastore_update_state(
-                                        &rpc_path,
-                                        pay_hash.to_string(),
-                                        HodlState::Open.to_string(),
-                                        generation,
-                                    )
-                                    .await
-                                    {
-                                        Ok(_o) => (),
-                                        Err(_e) => {
-                                            time::sleep(Duration::from_secs(2)).await;
-                                            continue;
-                                        }
-                                    };
+                                StateUpdate::Done => {
                                     info!(
                                         "payment_hash: `{}` scid: `{}` htlc: `{}`. No longer enough msats for the hodl-invoice. State=OPEN",
                                         pay_hash, scid, htlc_id
                                     );
                                 }
-                                *plugin
-                                    .state()
-                                    .invoice_amts
-                                    .lock()
-                                    .get_mut(&pay_hash.to_string())
-                                    .unwrap() -= amount_msat;
-                                return Ok(json!({"result": "fail"}));
-                            }
+                                StateUpdate::Retry => continue,
+                                StateUpdate::GiveUp => (),
+                            };
+                        }
+                        release_htlc(&plugin, pay_hash, &htlc_key, HtlcResult::Failed).await;
+                        return Ok(json!({"result": "fail"}));
+                    }
 
-                            match HodlState {
-                                HodlState::Open => {
-                                    if Amount::msat(&invoice.amount_msat.unwrap())
-                                        <= *plugin
-                                            .state()
-                                            .invoice_amts
-                                            .lock()
-                                            .get(&pay_hash.to_string())
-                                            .unwrap()
-                                        && HodlState.is_valid_transition(&HodlState::Accepted)
-                                    {
-                                        match datastore_update_state(
-                                            &rpc_path,
-                                            pay_hash.to_string(),
-                                            HodlState::Accepted.to_string(),
-                                            generation,
+                    match HodlState {
+                        HodlState::Open => {
+                            if entry.is_paid()
+                                && HodlState.is_valid_transition(&HodlState::Accepted)
+                            {
+                                match update_state(
+                                    &plugin,
+                                    pay_hash,
+                                    HodlState::Accepted,
+                                    generation,
+                                    &mut attempts,
+                                )
+                                .await
+                                {
+                                    StateUpdate::Done => (),
+                                    StateUpdate::Retry => continue,
+                                    StateUpdate::GiveUp => {
+                                        release_htlc(
+                                            &plugin,
+                                            pay_hash,
+                                            &htlc_key,
+                                            HtlcResult::Failed,
                                         )
-                                        .await
-                                        {
-                                            Ok(_o) => (),
-                                            Err(_e) => {
-                                                time::sleep(Duration::from_secs(2)).await;
-                                                continue;
-                                            }
-                                        };
-                                        info!(
-                                            "payment_hash: `{}` scid: `{}` htlc: `{}`. Got enough msats for the hodl-invoice. State=ACCEPTED",
-                                            pay_hash, scid, htlc_id
-                                        );
-                                    } else {
-                                        debug!(
-                                            "payment_hash: `{}` scid: `{}` htlc: `{}`. Not enough msats for the hodl-invoice yet.",
-                                            pay_hash, scid, htlc_id
-                                        );
+                                        .await;
+                                        return Ok(json!({"result": "fail"}));
                                     }
+                                };
+                                info!(
+                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Got enough msats for the hodl-invoice. State=ACCEPTED",
+                                    pay_hash, scid, htlc_id
+                                );
+                                if entry.reason.is_some() {
+                                    set_reason(&plugin, pay_hash, None).await;
                                 }
-                                HodlState::Accepted => {
-                                    if Amount::msat(&invoice.amount_msat.unwrap())
-                                        > *plugin
-                                            .state()
-                                            .invoice_amts
-                                            .lock()
-                                            .get(&pay_hash.to_string())
-                                            .unwrap()
-                                        && HodlState.is_valid_transition(&HodlState::Open)
-                                    {
-                                        match datastore_update_state(
-                                            &rpc_path,
-                                            pay_hash.to_string(),
-                                            HodlState::Open.to_string(),
-                                            generation,
+                            } else {
+                                debug!(
+                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Not enough msats for the hodl-invoice yet.",
+                                    pay_hash, scid, htlc_id
+                                );
+                            }
+                        }
+                        HodlState::Accepted => {
+                            if !entry.is_paid() && HodlState.is_valid_transition(&HodlState::Open) {
+                                match update_state(
+                                    &plugin,
+                                    pay_hash,
+                                    HodlState::Open,
+                                    generation,
+                                    &mut attempts,
+                                )
+                                .await
+                                {
+                                    StateUpdate::Done => (),
+                                    StateUpdate::Retry => continue,
+                                    StateUpdate::GiveUp => {
+                                        release_htlc(
+                                            &plugin,
+                                            pay_hash,
+                                            &htlc_key,
+                                            HtlcResult::Failed,
                                         )
-                                        .await
-                                        {
-                                            Ok(_o) => (),
-                                            Err(_e) => {
-                                                time::sleep(Duration::from_secs(2)).await;
-                                                continue;
-                                            }
-                                        };
-                                        info!(
-                                            "payment_hash: `{}` scid: `{}` htlc: `{}`. No longer enough msats for the hodl-invoice. State=OPEN",
-                                            pay_hash, scid, htlc_id
-                                        );
-                                    } else {
-                                        debug!(
-                                            "payment_hash: `{}` scid: `{}` htlc: `{}`. Holding accepted hodl-invoice.",
-                                            pay_hash, scid, htlc_id
-                                        );
+                                        .await;
+                                        return Ok(json!({"result": "fail"}));
                                     }
-                                }
-                                HodlState::Settled => {
-                                    info!(
-                                        "payment_hash: `{}` scid: `{}` htlc: `{}`. Settling htlc for hodl-invoice. State=SETTLED",
-                                        pay_hash, scid, htlc_id
-                                    );
-                                    return Ok(json!({"result": "continue"}));
-                                }
-                                HodlState::Canceled => {
-                                    info!(
-                                        "payment_hash: `{}` scid: `{}` htlc: `{}`. Rejecting htlc for canceled hodl-invoice.  State=CANCELED",
-                                        pay_hash, scid, htlc_id
-                                    );
-                                    return Ok(json!({"result": "fail"}));
-                                }
+                                };
+                                info!(
+                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. No longer enough msats for the hodl-invoice. State=OPEN",
+                                    pay_hash, scid, htlc_id
+                                );
+                            } else {
+                                debug!(
+                                    "payment_hash: `{}` scid: `{}` htlc: `{}`. Holding accepted hodl-invoice.",
+                                    pay_hash, scid, htlc_id
+                                );
                             }
                         }
-                        None => {
-                            warn!("payment_hash: `{}` scid: `{}` htlc: `{}`. DROPPED INVOICE from internal state!", pay_hash, scid, htlc_id);
-                            return Err(anyhow!(
-                                "Invoice dropped from internal state unexpectedly: {}",
-                                pay_hash
-                            ));
+                        HodlState::Settled => {
+                            info!(
+                                "payment_hash: `{}` scid: `{}` htlc: `{}`. Settling htlc for hodl-invoice. State=SETTLED",
+                                pay_hash, scid, htlc_id
+                            );
+                            plugin
+                                .state()
+                                .states
+                                .with_entry(pay_hash, |e| e.mark_settled(now))
+                                .await;
+                            release_htlc(&plugin, pay_hash, &htlc_key, HtlcResult::Settled).await;
+                            return Ok(json!({"result": "continue"}));
+                        }
+                        HodlState::Canceled => {
+                            info!(
+                                "payment_hash: `{}` scid: `{}` htlc: `{}`. Rejecting htlc for canceled hodl-invoice.  State=CANCELED",
+                                pay_hash, scid, htlc_id
+                            );
+                            release_htlc(&plugin, pay_hash, &htlc_key, HtlcResult::Failed).await;
+                            return Ok(json!({"result": "fail"}));
                         }
                     }
                 }
-                time::sleep(Duration::from_secs(3)).await;
+                None => {
+                    plugin.state().held.lock().release(&htlc_key);
+                    warn!("payment_hash: `{}` scid: `{}` htlc: `{}`. DROPPED INVOICE from internal state!", pay_hash, scid, htlc_id);
+                    return Err(anyhow!(
+                        "Invoice dropped from internal state unexpectedly: {}",
+                        pay_hash
+                    ));
+                }
             }
         }
+        time::sleep(Duration::from_secs(3)).await;
+    }
+}
+
+/// A payload we can't read. If it pays one of our hodl-invoices we fail
+/// it, lightningd would otherwise settle it right away.
+async fn malformed_payload(
+    plugin: &Plugin<PluginState>,
+    v: &serde_json::Value,
+    e: serde_json::Error,
+) -> serde_json::Value {
+    let pay_hash = v
+        .get("htlc")
+        .and_then(|h| h.get("payment_hash"))
+        .and_then(|p| p.as_str());
+    match pay_hash {
+        Some(pay_hash)
+            if plugin.state().states.get(pay_hash).is_some()
+                || list_datastore_state(&plugin.state().rpc, pay_hash.to_string())
+                    .await
+                    .is_ok() =>
+        {
+            warn!(
+                "payment_hash: `{}`. Malformed htlc_accepted payload: {}. Rejecting htlc...",
+                pay_hash, e
+            );
+            json!({"result": "fail"})
+        }
+        _ => {
+            warn!("Malformed htlc_accepted payload: {}. Continue...", e);
+            json!({"result": "continue"})
+        }
     }
-    warn!("htlc_accepted hook could not find htlc object");
-    Ok(json!({"result": "continue"}))
 }
 
+enum StateUpdate {
+    Done,
+    Retry,
+    GiveUp,
+}
+
+/// Move `pay_hash` to `new_state` if nobody wrote it since `generation`.
+/// On a conflict the fresh state is read back so the caller re-evaluates
+/// it, lightningd hiccups are retried with jittered backoff and after
+/// `MAX_STATE_UPDATE_ATTEMPTS` we give up and the htlc gets failed.
+async fn update_state(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    new_state: HodlState,
+    generation: u64,
+    attempts: &mut u32,
+) -> StateUpdate {
+    let rpc = &plugin.state().rpc;
+    let stats = &plugin.state().stats;
+    let _write = plugin.state().shutdown.write();
+    *attempts += 1;
+    let err =
+        match datastore_update_state(rpc, pay_hash.to_string(), new_state.to_string(), generation)
+            .await
+        {
+            Ok(o) => {
+                *attempts = 0;
+                let update = HodlUpdate {
+                    state: new_state,
+                    generation: o.generation.unwrap_or(generation + 1),
+                };
+                let old = plugin
+                    .state()
+                    .states
+                    .with_entry(pay_hash, |e| {
+                        let old = e.state;
+                        e.set_update(update);
+                        old
+                    })
+                    .await;
+                notifications::state_changed(plugin, pay_hash, old, new_state).await;
+                return StateUpdate::Done;
+            }
+            Err(e) => e,
+        };
+    match err {
+        DatastoreError::WrongGeneration => {
+            Stats::inc(&stats.datastore_conflicts);
+            debug!(
+                "payment_hash: `{}`. Generation {} is outdated, re-reading state...",
+                pay_hash, generation
+            );
+            match list_datastore_state(rpc, pay_hash.to_string()).await {
+                Ok(s) => match HodlState::from_str(&s.string.unwrap_or_default()) {
+                    Ok(state) => {
+                        plugin
+                            .state()
+                            .states
+                            .update(
+                                pay_hash,
+                                HodlUpdate {
+                                    state,
+                                    generation: s.generation.unwrap_or(0),
+                                },
+                            )
+                            .await;
+                    }
+                    Err(e) => warn!("payment_hash: `{}`. Bad state: {}", pay_hash, e),
+                },
+                Err(e) => warn!(
+                    "payment_hash: `{}`. Could not re-read state: {}",
+                    pay_hash, e
+                ),
+            }
+        }
+        DatastoreError::DoesNotExist => {
+            Stats::inc(&stats.datastore_missing);
+            warn!(
+                "payment_hash: `{}`. State vanished from datastore! Giving up...",
+                pay_hash
+            );
+            Stats::inc(&stats.datastore_give_ups);
+            return StateUpdate::GiveUp;
+        }
+        DatastoreError::Rejected(e) => {
+            Stats::inc(&stats.datastore_rejected);
+            warn!(
+                "payment_hash: `{}`. Could not set State={}: {}. Giving up...",
+                pay_hash,
+                new_state.to_string().to_uppercase(),
+                e
+            );
+            Stats::inc(&stats.datastore_give_ups);
+            return StateUpdate::GiveUp;
+        }
+        DatastoreError::Unavailable(e) => {
+            Stats::inc(&stats.datastore_unavailable);
+            warn!("payment_hash: `{}`. {}", pay_hash, e);
+            if *attempts < MAX_STATE_UPDATE_ATTEMPTS {
+                time::sleep(backoff(*attempts)).await;
+            }
+        }
+    }
+    if *attempts >= MAX_STATE_UPDATE_ATTEMPTS {
+        warn!(
+            "payment_hash: `{}`. Could not set State={} after {} attempts. Giving up...",
+            pay_hash,
+            new_state.to_string().to_uppercase(),
+            attempts
+        );
+        Stats::inc(&stats.datastore_give_ups);
+        return StateUpdate::GiveUp;
+    }
+    StateUpdate::Retry
+}
+
+fn backoff(attempt: u32) -> Duration {
+    let base = 500u64 << attempt.min(6);
+    Duration::from_millis(base + rand::thread_rng().gen_range(0..=base / 2))
+}
+
+/// Fail an htlc we would hold if maintenance mode is on or holding it
+/// breaks a `hodl-max-*` limit, otherwise it counts towards the limits.
+async fn reject_hold(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    htlc_key: &HtlcKey,
+    amount_msat: u64,
+) -> Option<serde_json::Value> {
+    if plugin.state().maintenance.load(Ordering::Relaxed) {
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Maintenance mode is on. Rejecting htlc...",
+            pay_hash, htlc_key.scid, htlc_key.id
+        );
+        Stats::inc(&plugin.state().stats.maintenance_rejections);
+        return Some(json!({
+            "result": "fail",
+            "failure_message": TEMPORARY_NODE_FAILURE,
+        }));
+    }
+    let limits = plugin.state().config.lock().limits();
+    let peer_id = if limits.needs_peers() {
+        peer_of(plugin, &htlc_key.scid).await
+    } else {
+        None
+    };
+    let reserved =
+        plugin
+            .state()
+            .held
+            .lock()
+            .reserve(&limits, htlc_key, peer_id.as_deref(), amount_msat);
+    if let Err(limit) = reserved {
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Holding {}msat would exceed {} limit. Rejecting htlc...",
+            pay_hash, htlc_key.scid, htlc_key.id, amount_msat, limit
+        );
+        Stats::inc(&plugin.state().stats.limit_rejections);
+        plugin
+            .state()
+            .metrics
+            .limit_rejections
+            .with_label_values(&[limit])
+            .inc();
+        return Some(json!({
+            "result": "fail",
+            "failure_message": TEMPORARY_CHANNEL_FAILURE,
+        }));
+    }
+    None
+}
+
+/// Store why we are canceling a hold, `None` removes a reason left behind
+/// by a cancel that didn't go through once the hodl-invoice is accepted.
+pub async fn set_reason(plugin: &Plugin<PluginState>, pay_hash: &str, reason: Option<HodlReason>) {
+    let rpc = &plugin.state().rpc;
+    let _write = plugin.state().shutdown.write();
+    let res = match reason {
+        Some(r) => datastore_reason(rpc, pay_hash.to_string(), r)
+            .await
+            .map(|_o| ())
+            .map_err(Error::from),
+        None => del_datastore_reason(rpc, pay_hash.to_string())
+            .await
+            .map(|_o| ()),
+    };
+    match res {
+        Ok(()) => {
+            plugin
+                .state()
+                .states
+                .with_entry(pay_hash, |e| e.reason = reason)
+                .await;
+        }
+        Err(e) => warn!(
+            "payment_hash: `{}`. Could not store reason {:?}: {}",
+            pay_hash, reason, e
+        ),
+    }
+}
+
+/// `incorrect_or_unknown_payment_details` with the htlc amount and our
+/// current block height as BOLT 4 wants it.
+fn incorrect_or_unknown_payment_details(amount_msat: u64, blockheight: u32) -> String {
+    format!("400f{:016x}{:08x}", amount_msat, blockheight)
+}
+
+/// Keep what the sender put into the onion of a held htlc, so whoever
+/// settles can look at it with `hodl-lookup`.
+async fn store_onion(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    htlc_key: &HtlcKey,
+    amount_msat: u64,
+    onion: &Onion,
+) {
+    let details = match onion.details() {
+        Ok(d) => d,
+        Err(e) => {
+            warn!(
+                "payment_hash: `{}` scid: `{}` htlc: `{}`. Unreadable onion payload: {}",
+                pay_hash, htlc_key.scid, htlc_key.id, e
+            );
+            HtlcOnion::default()
+        }
+    };
+    let mut record = json!(details);
+    record["short_channel_id"] = json!(htlc_key.scid);
+    record["id"] = json!(htlc_key.id);
+    record["amount_msat"] = json!(amount_msat);
+    let _write = plugin.state().shutdown.write();
+    if let Err(e) = datastore_onion(
+        &plugin.state().rpc,
+        pay_hash.to_string(),
+        format!("{}-{}", htlc_key.scid, htlc_key.id),
+        record.to_string(),
+    )
+    .await
+    {
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Could not store onion: {}",
+            pay_hash, htlc_key.scid, htlc_key.id, e
+        );
+    }
+}
+
+async fn release_htlc(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    htlc_key: &HtlcKey,
+    result: HtlcResult,
+) {
+    let held = plugin
+        .state()
+        .states
+        .with_entry(pay_hash, |e| e.release_htlc(htlc_key))
+        .await
+        .flatten();
+    plugin.state().held.lock().release(htlc_key);
+    forget_held(plugin, htlc_key).await;
+    if let Some(h) = held {
+        plugin
+            .state()
+            .metrics
+            .hold_duration
+            .observe(h.held_at.elapsed().as_secs_f64());
+    }
+    notifications::htlc_released(
+        plugin,
+        pay_hash,
+        htlc_key,
+        held.map(|h| h.amount_msat),
+        result,
+    )
+    .await;
+}
+
+/// Follow the chain tip. `blockheight` only ever grows, so a reorg can't
+/// make us hold an htlc closer to its cltv expiry than we would have.
 pub async fn block_added(plugin: Plugin<PluginState>, v: serde_json::Value) -> Result<(), Error> {
-    match v.get("block") {
-        Some(block) => match block.get("height") {
-            Some(h) => *plugin.state().blockheight.lock() = h.as_u64().unwrap() as u32,
-            None => return Err(anyhow!("could not find height for block")),
+    let (height, hash) = match v.get("block") {
+        Some(block) => match (
+            block.get("height").and_then(|h| h.as_u64()),
+            block.get("hash").and_then(|h| h.as_str()),
+        ) {
+            (Some(height), Some(hash)) => (height as u32, hash),
+            _ => return Err(anyhow!("could not find height and hash for block")),
         },
         None => return Err(anyhow!("could not read block notification")),
     };
+    let (reorg, safe_height) = {
+        let mut chain = plugin.state().chain.lock();
+        (chain.block_added(height, hash), chain.safe_height())
+    };
+    if let Some(reorg) = reorg {
+        Stats::inc(&plugin.state().stats.reorgs);
+        warn!(
+            "Reorg of {} blocks, new block {} at height {} replaces our tip at {}. Keeping cltv checks at height {}",
+            reorg.depth(),
+            hash,
+            reorg.to_height,
+            reorg.from_height,
+            safe_height
+        );
+    }
+    *plugin.state().blockheight.lock() = safe_height;
+    Ok(())
+}
+
+/// lightningd resolved an invoice. For a hodl-invoice this confirms that
+/// settling it actually went through, which we keep under its `paid` key.
+pub async fn invoice_payment(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    let label = match v
+        .get("invoice_payment")
+        .and_then(|p| p.get("label"))
+        .and_then(|l| l.as_str())
+    {
+        Some(l) => l,
+        None => return Err(anyhow!("could not read invoice_payment notification")),
+    };
+    let rpc = &plugin.state().rpc;
+    let invoice = match listinvoices(rpc, Some(label.to_string()), None, None)
+        .await?
+        .invoices
+        .into_iter()
+        .next()
+    {
+        Some(i) => i,
+        None => return Err(anyhow!("invoice_payment for unknown label: {}", label)),
+    };
+    let pay_hash = invoice.payment_hash.to_string();
+    if plugin.state().states.get(&pay_hash).is_none()
+        && list_datastore_state(rpc, pay_hash.clone()).await.is_err()
+    {
+        debug!(
+            "payment_hash: `{}`. Not a hodl-invoice, ignoring payment",
+            pay_hash
+        );
+        return Ok(());
+    }
+
+    let paid = HodlPaid {
+        paid_at: invoice.paid_at.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        }),
+        amount_received_msat: invoice
+            .amount_received_msat
+            .map(|a| Amount::msat(&a))
+            .unwrap_or_default(),
+    };
+    let write = plugin.state().shutdown.write();
+    datastore_paid(rpc, pay_hash.clone(), paid.to_json().to_string()).await?;
+    drop(write);
+    plugin
+        .state()
+        .states
+        .with_entry(&pay_hash, |e| e.paid = Some(paid))
+        .await;
+    Stats::inc(&plugin.state().stats.settle_confirmed);
+    info!(
+        "payment_hash: `{}`. Hodl-invoice paid, received {}msat",
+        pay_hash, paid.amount_received_msat
+    );
     Ok(())
 }
@@ -0,0 +1,598 @@
+use std::{collections::BTreeMap, future::Future, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::{model::ListinvoicesInvoices, primitives::Amount};
+use log::debug;
+use parking_lot::Mutex;
+
+use crate::{
+    state::{hodl_outcome, HodlReason, HodlState},
+    HodlUpdate,
+};
+
+/// Everything we know about one hodl-invoice. The htlcs currently held for
+/// it are only touched through `hold_htlc`/`release_htlc` so the held amount
+/// can't drift from the htlcs we actually hold.
+#[derive(Clone, Debug)]
+pub struct HodlInvoiceEntry {
+    pub state: HodlState,
+    pub generation: u64,
+    pub invoice: ListinvoicesInvoices,
+    htlcs: BTreeMap<HtlcKey, HeldHtlc>,
+    /// When we first let htlcs through after the state became `settled`.
+    pub settled_at: Option<u64>,
+    /// lightningd's confirmation that the invoice is actually paid.
+    pub paid: Option<HodlPaid>,
+    pub reason: Option<HodlReason>,
+    settle_alerted: bool,
+    /// The invoice's payment secret, every part has to carry it.
+    pub payment_secret: Option<String>,
+    /// `total_msat` of the parts we hold, they all have to agree on it.
+    total_msat: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HodlPaid {
+    pub paid_at: u64,
+    pub amount_received_msat: u64,
+}
+impl HodlPaid {
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "paid_at": self.paid_at,
+            "amount_received_msat": self.amount_received_msat,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HtlcKey {
+    pub scid: String,
+    pub id: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeldHtlc {
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    pub held_at: Instant,
+    /// Set by `hodl-release-all` or `hodl-channel-policy`, the htlc gets
+    /// failed on the next check.
+    pub release_requested: bool,
+}
+
+impl HodlInvoiceEntry {
+    pub fn new(
+        update: HodlUpdate,
+        invoice: ListinvoicesInvoices,
+    ) -> Result<HodlInvoiceEntry, Error> {
+        if invoice.amount_msat.is_none() {
+            return Err(anyhow!(
+                "payment_hash: `{}`. Hodl-invoice has no amount!",
+                invoice.payment_hash
+            ));
+        }
+        Ok(HodlInvoiceEntry {
+            state: update.state,
+            generation: update.generation,
+            invoice,
+            htlcs: BTreeMap::new(),
+            settled_at: None,
+            paid: None,
+            reason: None,
+            settle_alerted: false,
+            payment_secret: None,
+            total_msat: None,
+        })
+    }
+
+    pub fn set_update(&mut self, update: HodlUpdate) {
+        self.state = update.state;
+        self.generation = update.generation;
+    }
+
+    pub fn amount_msat(&self) -> u64 {
+        Amount::msat(&self.invoice.amount_msat.unwrap())
+    }
+
+    pub fn held_msat(&self) -> u64 {
+        self.htlcs.values().map(|h| h.amount_msat).sum()
+    }
+
+    pub fn htlcs(&self) -> &BTreeMap<HtlcKey, HeldHtlc> {
+        &self.htlcs
+    }
+
+    /// Do the held htlcs add up to the invoice amount and to the
+    /// `total_msat` the sender announced?
+    pub fn is_paid(&self) -> bool {
+        let total = if self.htlcs.is_empty() {
+            0
+        } else {
+            self.total_msat.unwrap_or(0)
+        };
+        self.held_msat() >= self.amount_msat().max(total)
+    }
+
+    /// Check one part of a payment before holding it: it must carry the
+    /// invoice's payment secret and the `total_msat` of the parts we
+    /// already hold, which has to cover the invoice amount.
+    pub fn check_part(
+        &mut self,
+        payment_secret: Option<&str>,
+        total_msat: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some(expected) = self.payment_secret.as_deref() {
+            if !payment_secret.is_some_and(|s| s.eq_ignore_ascii_case(expected)) {
+                return Err(anyhow!(
+                    "payment_hash: `{}`. Wrong payment_secret `{}`",
+                    self.invoice.payment_hash,
+                    payment_secret.unwrap_or("none")
+                ));
+            }
+        }
+        if let Some(total) = total_msat {
+            if total < self.amount_msat() {
+                return Err(anyhow!(
+                    "payment_hash: `{}`. total_msat {} is less than the invoice amount {}",
+                    self.invoice.payment_hash,
+                    total,
+                    self.amount_msat()
+                ));
+            }
+        }
+        if self.htlcs.is_empty() {
+            self.total_msat = total_msat;
+        } else if self.total_msat != total_msat {
+            return Err(anyhow!(
+                "payment_hash: `{}`. total_msat {:?} differs from {:?} of the held parts",
+                self.invoice.payment_hash,
+                total_msat,
+                self.total_msat
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn hold_htlc(
+        &mut self,
+        key: HtlcKey,
+        amount_msat: u64,
+        cltv_expiry: u32,
+    ) -> Result<(), Error> {
+        if self.htlcs.contains_key(&key) {
+            return Err(anyhow!(
+                "payment_hash: `{}` scid: `{}` htlc_id: `{}`. Htlc is already held!",
+                self.invoice.payment_hash,
+                key.scid,
+                key.id
+            ));
+        }
+        self.htlcs.insert(
+            key,
+            HeldHtlc {
+                amount_msat,
+                cltv_expiry,
+                held_at: Instant::now(),
+                release_requested: false,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn release_htlc(&mut self, key: &HtlcKey) -> Option<HeldHtlc> {
+        self.htlcs.remove(key)
+    }
+
+    pub fn outcome(&self) -> Option<&'static str> {
+        hodl_outcome(self.state, self.reason)
+    }
+
+    /// Ask for every htlc expiring at or before `cltv_expiry` to be failed,
+    /// returns how many that are.
+    pub fn request_release(&mut self, cltv_expiry: u32) -> usize {
+        let mut count = 0;
+        for htlc in self.htlcs.values_mut() {
+            if htlc.cltv_expiry <= cltv_expiry {
+                htlc.release_requested = true;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Ask for one htlc to be failed, false if we don't hold it.
+    pub fn request_release_htlc(&mut self, key: &HtlcKey) -> bool {
+        match self.htlcs.get_mut(key) {
+            Some(htlc) => {
+                htlc.release_requested = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_settled(&mut self, now: u64) {
+        self.settled_at.get_or_insert(now);
+    }
+
+    /// True exactly once if we settled more than `deadline` seconds ago and
+    /// lightningd still hasn't confirmed the payment.
+    pub fn settle_overdue(&mut self, now: u64, deadline: u64) -> bool {
+        match self.settled_at {
+            Some(settled_at)
+                if self.paid.is_none() && !self.settle_alerted && settled_at + deadline <= now =>
+            {
+                self.settle_alerted = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HodlStateChange {
+    pub pay_hash: String,
+    pub old: HodlUpdate,
+    pub new: HodlUpdate,
+}
+
+pub type HodlSlot = Arc<tokio::sync::Mutex<Option<HodlInvoiceEntry>>>;
+
+/// Tracked hodl-invoices keyed by payment hash. Every hash gets its own
+/// async lock, the map itself is only ever locked for a lookup, so a slow
+/// RPC call for one invoice never stalls htlcs of another one.
+#[derive(Clone, Debug, Default)]
+pub struct HodlInvoices {
+    slots: Arc<Mutex<BTreeMap<String, HodlSlot>>>,
+}
+impl HodlInvoices {
+    pub fn new() -> HodlInvoices {
+        HodlInvoices::default()
+    }
+
+    pub fn get(&self, pay_hash: &str) -> Option<HodlSlot> {
+        self.slots.lock().get(pay_hash).cloned()
+    }
+
+    /// Snapshot of `pay_hash`, if we track it.
+    pub async fn entry(&self, pay_hash: &str) -> Option<HodlInvoiceEntry> {
+        match self.get(pay_hash) {
+            Some(slot) => slot.lock().await.clone(),
+            None => None,
+        }
+    }
+
+    pub fn pay_hashes(&self) -> Vec<String> {
+        self.slots.lock().keys().cloned().collect()
+    }
+
+    /// Run `f` on the entry of `pay_hash`, if we track it.
+    pub async fn with_entry<R, F>(&self, pay_hash: &str, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut HodlInvoiceEntry) -> R,
+    {
+        let slot = self.get(pay_hash)?;
+        let mut entry = slot.lock().await;
+        entry.as_mut().map(f)
+    }
+
+    /// Overwrite the state of an invoice we already track.
+    pub async fn update(&self, pay_hash: &str, update: HodlUpdate) {
+        self.with_entry(pay_hash, |e| e.set_update(update)).await;
+    }
+
+    /// Take over `updates` read from the datastore for the invoices we
+    /// track and return the ones whose generation moved.
+    pub async fn apply_updates(
+        &self,
+        updates: &BTreeMap<String, HodlUpdate>,
+    ) -> Vec<HodlStateChange> {
+        let mut changes = Vec::new();
+        for pay_hash in self.pay_hashes() {
+            let update = match updates.get(&pay_hash) {
+                Some(u) => *u,
+                None => {
+                    debug!("pay_hash: {} has no readable state", pay_hash);
+                    continue;
+                }
+            };
+            let change = self
+                .with_entry(&pay_hash, |e| {
+                    // a snapshot from before our own last write is stale
+                    if update.generation <= e.generation {
+                        return None;
+                    }
+                    let old = HodlUpdate {
+                        state: e.state,
+                        generation: e.generation,
+                    };
+                    e.set_update(update);
+                    Some(old)
+                })
+                .await
+                .flatten();
+            if let Some(old) = change {
+                changes.push(HodlStateChange {
+                    pay_hash,
+                    old,
+                    new: update,
+                });
+            }
+        }
+        changes
+    }
+
+    pub fn retain<F: FnMut(&String) -> bool>(&self, mut keep: F) {
+        self.slots.lock().retain(|hash, _| keep(hash));
+    }
+
+    /// Return the entry of `pay_hash`, calling `load` if we don't know it
+    /// yet. Concurrent callers for the same hash wait for the first `load`,
+    /// callers for other hashes are not affected by it. `load` returning
+    /// `None` means this is not a hodl-invoice and nothing is kept.
+    pub async fn get_or_load<F, Fut>(
+        &self,
+        pay_hash: &str,
+        load: F,
+    ) -> Result<Option<HodlInvoiceEntry>, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<HodlInvoiceEntry>, Error>>,
+    {
+        let slot = self
+            .slots
+            .lock()
+            .entry(pay_hash.to_string())
+            .or_default()
+            .clone();
+        let mut entry = slot.lock().await;
+        if entry.is_some() {
+            return Ok(entry.clone());
+        }
+        let loaded = match load().await {
+            Ok(l) => l,
+            Err(e) => {
+                self.forget(pay_hash, &slot);
+                return Err(e);
+            }
+        };
+        match loaded {
+            Some(e) => {
+                *entry = Some(e);
+                // a concurrent caller may have dropped our empty slot meanwhile
+                self.slots
+                    .lock()
+                    .entry(pay_hash.to_string())
+                    .or_insert_with(|| slot.clone());
+            }
+            None => self.forget(pay_hash, &slot),
+        }
+        Ok(entry.clone())
+    }
+
+    fn forget(&self, pay_hash: &str, slot: &HodlSlot) {
+        let mut slots = self.slots.lock();
+        if let Some(s) = slots.get(pay_hash) {
+            if Arc::ptr_eq(s, slot) {
+                slots.remove(pay_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    fn open() -> HodlInvoiceEntry {
+        let invoice = serde_json::from_value(serde_json::json!({
+            "label": "hodl",
+            "payment_hash": "0000000000000000000000000000000000000000000000000000000000000000",
+            "status": "unpaid",
+            "expires_at": 1_700_000_000u64,
+            "amount_msat": 10_000,
+        }))
+        .unwrap();
+        HodlInvoiceEntry::new(
+            HodlUpdate {
+                state: HodlState::Open,
+                generation: 0,
+            },
+            invoice,
+        )
+        .unwrap()
+    }
+
+    fn htlc(id: u64) -> HtlcKey {
+        HtlcKey {
+            scid: "1x1x1".to_string(),
+            id,
+        }
+    }
+
+    #[test]
+    fn held_amount_follows_htlcs() {
+        let mut entry = open();
+        entry.hold_htlc(htlc(0), 6_000, 800_000).unwrap();
+        assert!(!entry.is_paid());
+        entry.hold_htlc(htlc(1), 4_000, 800_000).unwrap();
+        assert!(entry.is_paid());
+        assert!(entry.hold_htlc(htlc(1), 4_000, 800_000).is_err());
+        assert_eq!(entry.held_msat(), 10_000);
+        assert_eq!(
+            entry.release_htlc(&htlc(0)).map(|h| h.amount_msat),
+            Some(6_000)
+        );
+        assert!(entry.release_htlc(&htlc(0)).is_none());
+        assert_eq!(entry.held_msat(), 4_000);
+        assert!(!entry.is_paid());
+    }
+
+    #[test]
+    fn mpp_parts_must_agree() {
+        let mut entry = open();
+        entry.payment_secret = Some("ab".repeat(32));
+        let secret = "AB".repeat(32);
+
+        assert!(entry.check_part(None, Some(10_000)).is_err());
+        let wrong = "cd".repeat(32);
+        assert!(entry.check_part(Some(&wrong), Some(10_000)).is_err());
+        assert!(entry.check_part(Some(&secret), Some(9_999)).is_err());
+
+        entry.check_part(Some(&secret), Some(12_000)).unwrap();
+        entry.hold_htlc(htlc(0), 6_000, 800_000).unwrap();
+        assert!(entry.check_part(Some(&secret), Some(10_000)).is_err());
+        assert!(entry.check_part(Some(&secret), None).is_err());
+        entry.check_part(Some(&secret), Some(12_000)).unwrap();
+        entry.hold_htlc(htlc(1), 4_000, 800_000).unwrap();
+        // enough for the invoice but not for what the sender announced
+        assert!(!entry.is_paid());
+        entry.check_part(Some(&secret), Some(12_000)).unwrap();
+        entry.hold_htlc(htlc(2), 2_000, 800_000).unwrap();
+        assert!(entry.is_paid());
+
+        // a fresh set may announce another total
+        for id in 0..3 {
+            entry.release_htlc(&htlc(id));
+        }
+        entry.check_part(Some(&secret), Some(10_000)).unwrap();
+    }
+
+    #[test]
+    fn unconfirmed_settlement_alerts_once() {
+        let mut entry = open();
+        assert!(!entry.settle_overdue(1_000, 60));
+        entry.mark_settled(1_000);
+        entry.mark_settled(1_030);
+        assert!(!entry.settle_overdue(1_059, 60));
+        assert!(entry.settle_overdue(1_060, 60));
+        assert!(!entry.settle_overdue(1_120, 60));
+
+        let mut entry = open();
+        entry.mark_settled(1_000);
+        entry.paid = Some(HodlPaid {
+            paid_at: 1_010,
+            amount_received_msat: 10_000,
+        });
+        assert!(!entry.settle_overdue(2_000, 60));
+    }
+
+    #[tokio::test]
+    async fn different_hashes_load_in_parallel() {
+        let invoices = HodlInvoices::new();
+        let b_loaded = Arc::new(Notify::new());
+
+        // `a` can only finish loading once `b` has loaded, so this
+        // deadlocks if loading `a` blocks the whole map.
+        let slow = async {
+            invoices
+                .get_or_load("a", || async {
+                    b_loaded.notified().await;
+                    Ok(Some(open()))
+                })
+                .await
+        };
+        let fast = async {
+            tokio::task::yield_now().await;
+            let res = invoices
+                .get_or_load("b", || async { Ok(Some(open())) })
+                .await;
+            b_loaded.notify_one();
+            res
+        };
+        let (a, b) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(slow, fast) })
+                .await
+                .expect("htlcs for different hashes blocked each other");
+        assert!(a.unwrap().is_some());
+        assert!(b.unwrap().is_some());
+        assert_eq!(invoices.pay_hashes(), vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn same_hash_loads_once() {
+        let invoices = HodlInvoices::new();
+        let first = invoices
+            .get_or_load("a", || async { Ok(Some(open())) })
+            .await;
+        let second = invoices
+            .get_or_load("a", || async { panic!("loaded twice") })
+            .await;
+        assert_eq!(first.unwrap().unwrap().state, HodlState::Open);
+        assert_eq!(second.unwrap().unwrap().state, HodlState::Open);
+    }
+
+    #[tokio::test]
+    async fn only_changed_generations_are_reported() {
+        let invoices = HodlInvoices::new();
+        for hash in ["a", "b"] {
+            invoices
+                .get_or_load(hash, || async { Ok(Some(open())) })
+                .await
+                .unwrap();
+        }
+        let mut updates = BTreeMap::new();
+        updates.insert(
+            "a".to_string(),
+            HodlUpdate {
+                state: HodlState::Open,
+                generation: 0,
+            },
+        );
+        updates.insert(
+            "b".to_string(),
+            HodlUpdate {
+                state: HodlState::Canceled,
+                generation: 1,
+            },
+        );
+        let changes = invoices.apply_updates(&updates).await;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].pay_hash, "b");
+        assert_eq!(changes[0].old.state, HodlState::Open);
+        assert_eq!(changes[0].new.state, HodlState::Canceled);
+        assert_eq!(
+            invoices.entry("b").await.unwrap().state,
+            HodlState::Canceled
+        );
+        assert!(invoices.apply_updates(&updates).await.is_empty());
+
+        // we wrote generation 3 ourselves, a listdatastore from before that
+        // must not roll it back
+        invoices
+            .update(
+                "b",
+                HodlUpdate {
+                    state: HodlState::Accepted,
+                    generation: 3,
+                },
+            )
+            .await;
+        updates.insert(
+            "b".to_string(),
+            HodlUpdate {
+                state: HodlState::Open,
+                generation: 2,
+            },
+        );
+        assert!(invoices.apply_updates(&updates).await.is_empty());
+        assert_eq!(
+            invoices.entry("b").await.unwrap().state,
+            HodlState::Accepted
+        );
+    }
+
+    #[tokio::test]
+    async fn non_hodl_invoices_are_not_kept() {
+        let invoices = HodlInvoices::new();
+        let res = invoices.get_or_load("a", || async { Ok(None) }).await;
+        assert!(res.unwrap().is_none());
+        assert!(invoices.get("a").is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    payload::{HtlcAccepted, HtlcOnion},
+    state::{datastore_new_state, list_datastore_state, HodlState},
+    util::{invoice, listinvoices},
+    PluginState,
+};
+
+/// TLV record carrying the preimage of a keysend payment.
+pub const KEYSEND_PREIMAGE_TLV: u64 = 5_482_373_484;
+/// Labels of the invoices we create for held keysend payments.
+const KEYSEND_LABEL_PREFIX: &str = "hodl-keysend-";
+/// lightningd's keysend plugin labels its invoices like this.
+const CLN_KEYSEND_LABEL_PREFIX: &str = "keysend-";
+
+/// A custom TLV record a keysend payment must carry to be held, with
+/// the exact hex value if one is given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeysendRecord {
+    pub tlv_type: u64,
+    pub value: Option<String>,
+}
+
+/// Parse `hodl-keysend-records`, comma separated `type` or `type=hex`.
+pub fn parse_keysend_records(value: &str) -> Result<Vec<KeysendRecord>, Error> {
+    let mut records = Vec::new();
+    for record in value.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+        let (tlv_type, value) = match record.split_once('=') {
+            Some((t, v)) => (t, Some(v.trim().to_lowercase())),
+            None => (record, None),
+        };
+        if let Some(v) = &value {
+            hex::decode(v).map_err(|e| anyhow!("`{}` is not hex: {}", v, e))?;
+        }
+        records.push(KeysendRecord {
+            tlv_type: tlv_type.trim().parse::<u64>()?,
+            value,
+        });
+    }
+    Ok(records)
+}
+
+/// Which keysend payments `hodl-keysend` holds.
+#[derive(Clone, Debug, Default)]
+pub struct KeysendRules {
+    pub enabled: bool,
+    pub min_msat: Option<u64>,
+    pub max_msat: Option<u64>,
+    /// All of them have to be present.
+    pub records: Vec<KeysendRecord>,
+}
+impl KeysendRules {
+    pub fn matches(&self, amount_msat: u64, onion: &HtlcOnion) -> bool {
+        self.enabled
+            && amount_msat >= self.min_msat.unwrap_or(0)
+            && amount_msat <= self.max_msat.unwrap_or(u64::MAX)
+            && self.records.iter().all(|r| {
+                onion
+                    .custom_records
+                    .get(&r.tlv_type)
+                    .is_some_and(|v| r.value.is_none() || r.value.as_ref() == Some(v))
+            })
+    }
+}
+
+/// Invoices lightningd or we created for a keysend payment have no
+/// payment secret the parts could carry.
+pub fn is_keysend_label(label: &str) -> bool {
+    label.starts_with(KEYSEND_LABEL_PREFIX) || label.starts_with(CLN_KEYSEND_LABEL_PREFIX)
+}
+
+/// A keysend payment `hodl-keysend` wants held.
+#[derive(Clone, Debug)]
+pub struct HeldKeysend {
+    preimage: String,
+    amount_msat: u64,
+}
+
+/// The keysend payment in `payload` if `hodl-keysend` wants it held.
+pub fn keysend_to_hold(
+    plugin: &Plugin<PluginState>,
+    payload: &HtlcAccepted,
+) -> Result<Option<HeldKeysend>, Error> {
+    let rules = plugin.state().config.lock().keysend_rules();
+    if !rules.enabled {
+        return Ok(None);
+    }
+    let onion = payload.onion.details()?;
+    let preimage = match onion.custom_records.get(&KEYSEND_PREIMAGE_TLV) {
+        Some(p) => p.clone(),
+        None => return Ok(None),
+    };
+    let pay_hash = payload.htlc.payment_hash.as_str();
+    if hex::encode(Sha256::digest(hex::decode(&preimage)?)) != pay_hash.to_lowercase() {
+        return Err(anyhow!(
+            "payment_hash: `{}`. Keysend preimage does not match",
+            pay_hash
+        ));
+    }
+    let amount_msat = onion.total_msat.unwrap_or(payload.htlc.amount_msat);
+    if !rules.matches(amount_msat, &onion) {
+        debug!(
+            "payment_hash: `{}`. Keysend payment does not match hodl-keysend rules",
+            pay_hash
+        );
+        return Ok(None);
+    }
+    Ok(Some(HeldKeysend {
+        preimage,
+        amount_msat,
+    }))
+}
+
+/// Turn a keysend payment `keysend_to_hold` picked into a hodl-invoice: an
+/// invoice with the sender's preimage and an `open` state, settled or
+/// canceled like any other hodl-invoice.
+pub async fn register_keysend(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    keysend: HeldKeysend,
+) -> Result<(), Error> {
+    let HeldKeysend {
+        preimage,
+        amount_msat,
+    } = keysend;
+    let rpc = &plugin.state().rpc;
+    if list_datastore_state(rpc, pay_hash.to_string())
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+
+    let expiry = plugin.state().config.lock().keysend_expiry.1;
+    if let Err(e) = invoice(
+        rpc,
+        format!("{}{}", KEYSEND_LABEL_PREFIX, pay_hash),
+        "hodl keysend".to_string(),
+        amount_msat,
+        expiry,
+        preimage,
+    )
+    .await
+    {
+        // lightningd's keysend plugin may have been first
+        if listinvoices(rpc, None, Some(pay_hash.to_string()), None)
+            .await?
+            .invoices
+            .is_empty()
+        {
+            return Err(e);
+        }
+        warn!(
+            "payment_hash: `{}`. Holding keysend payment with its existing invoice",
+            pay_hash
+        );
+    }
+    datastore_new_state(rpc, pay_hash.to_string(), HodlState::Open.to_string()).await?;
+    info!(
+        "payment_hash: `{}`. Holding keysend payment of {}msat. State=OPEN",
+        pay_hash, amount_msat
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn keysend_rules() {
+        let records = parse_keysend_records("65537, 7629169=AB01").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                KeysendRecord {
+                    tlv_type: 65_537,
+                    value: None
+                },
+                KeysendRecord {
+                    tlv_type: 7_629_169,
+                    value: Some("ab01".to_string())
+                },
+            ]
+        );
+        assert!(parse_keysend_records("65537=xyz").is_err());
+
+        let rules = KeysendRules {
+            enabled: true,
+            min_msat: Some(1_000),
+            max_msat: Some(5_000),
+            records,
+        };
+        let onion = HtlcOnion {
+            custom_records: BTreeMap::from([
+                (65_537, "00".to_string()),
+                (7_629_169, "ab01".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert!(rules.matches(1_000, &onion));
+        assert!(!rules.matches(999, &onion));
+        assert!(!rules.matches(5_001, &onion));
+        let wrong_value = HtlcOnion {
+            custom_records: BTreeMap::from([
+                (65_537, "00".to_string()),
+                (7_629_169, "ab02".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert!(!rules.matches(1_000, &wrong_value));
+        assert!(!KeysendRules::default().matches(1_000, &onion));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+
+use cln_plugin::Plugin;
+use log::warn;
+
+use crate::{exposure::channel_infos, invoices::HtlcKey, PluginState};
+
+/// `temporary_node_failure`, the sender may try again later.
+pub const TEMPORARY_NODE_FAILURE: &str = "2002";
+/// `temporary_channel_failure` with an empty `channel_update`, what an htlc
+/// that would break a `hodl-max-*` limit is failed with. It keeps limit
+/// rejections apart from the `temporary_node_failure` of maintenance mode.
+pub const TEMPORARY_CHANNEL_FAILURE: &str = "10070000";
+
+/// Caps on what we hold at once, `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub total_msat: Option<u64>,
+    pub total_htlcs: Option<u64>,
+    pub channel_msat: Option<u64>,
+    pub channel_htlcs: Option<u64>,
+    pub peer_msat: Option<u64>,
+    pub peer_htlcs: Option<u64>,
+}
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.total_msat.is_none()
+            && self.total_htlcs.is_none()
+            && self.channel_msat.is_none()
+            && self.channel_htlcs.is_none()
+            && self.peer_msat.is_none()
+            && self.peer_htlcs.is_none()
+    }
+
+    pub fn needs_peers(&self) -> bool {
+        self.peer_msat.is_some() || self.peer_htlcs.is_some()
+    }
+}
+
+/// Held msat and htlcs of one channel, one peer or all of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub msat: u64,
+    pub htlcs: u64,
+}
+impl Tally {
+    /// Would one more htlc of `amount_msat` break `(max_msat, max_htlcs)`?
+    /// Returns the name of the broken limit.
+    fn exceeds(
+        &self,
+        max_msat: (Option<u64>, &'static str),
+        max_htlcs: (Option<u64>, &'static str),
+        amount_msat: u64,
+    ) -> Option<&'static str> {
+        if max_msat.0.is_some_and(|m| self.msat + amount_msat > m) {
+            return Some(max_msat.1);
+        }
+        if max_htlcs.0.is_some_and(|m| self.htlcs + 1 > m) {
+            return Some(max_htlcs.1);
+        }
+        None
+    }
+
+    fn add(&mut self, amount_msat: u64) {
+        self.msat += amount_msat;
+        self.htlcs += 1;
+    }
+
+    fn sub(&mut self, amount_msat: u64) {
+        self.msat = self.msat.saturating_sub(amount_msat);
+        self.htlcs = self.htlcs.saturating_sub(1);
+    }
+}
+
+/// Running totals of every htlc we hold, updated as htlcs are held and
+/// released. Checking the limits and counting the htlc happen in one
+/// `reserve` under the caller's lock, so two htlcs can't both squeeze
+/// under a cap.
+#[derive(Debug, Default)]
+pub struct HeldTotals {
+    total: Tally,
+    channels: BTreeMap<String, Tally>,
+    peers: BTreeMap<String, Tally>,
+    htlcs: BTreeMap<HtlcKey, (u64, Option<String>)>,
+}
+impl HeldTotals {
+    /// Count `key` as held unless that breaks one of `limits`, then the
+    /// name of the broken limit is returned, e.g. `channel_htlcs`. Without
+    /// `peer_id` the peer limits are skipped.
+    pub fn reserve(
+        &mut self,
+        limits: &Limits,
+        key: &HtlcKey,
+        peer_id: Option<&str>,
+        amount_msat: u64,
+    ) -> Result<(), &'static str> {
+        if self.htlcs.contains_key(key) {
+            return Ok(());
+        }
+        let channel = self.channels.get(&key.scid).copied().unwrap_or_default();
+        let peer = peer_id
+            .and_then(|p| self.peers.get(p))
+            .copied()
+            .unwrap_or_default();
+        let broken = self
+            .total
+            .exceeds(
+                (limits.total_msat, "total_msat"),
+                (limits.total_htlcs, "total_htlcs"),
+                amount_msat,
+            )
+            .or_else(|| {
+                channel.exceeds(
+                    (limits.channel_msat, "channel_msat"),
+                    (limits.channel_htlcs, "channel_htlcs"),
+                    amount_msat,
+                )
+            })
+            .or_else(|| {
+                peer_id.and_then(|_p| {
+                    peer.exceeds(
+                        (limits.peer_msat, "peer_msat"),
+                        (limits.peer_htlcs, "peer_htlcs"),
+                        amount_msat,
+                    )
+                })
+            });
+        if let Some(limit) = broken {
+            return Err(limit);
+        }
+        self.total.add(amount_msat);
+        self.channels
+            .entry(key.scid.clone())
+            .or_default()
+            .add(amount_msat);
+        if let Some(p) = peer_id {
+            self.peers
+                .entry(p.to_string())
+                .or_default()
+                .add(amount_msat);
+        }
+        self.htlcs
+            .insert(key.clone(), (amount_msat, peer_id.map(|p| p.to_string())));
+        Ok(())
+    }
+
+    /// Stop counting `key`, nothing happens if it wasn't reserved.
+    pub fn release(&mut self, key: &HtlcKey) {
+        let (amount_msat, peer_id) = match self.htlcs.remove(key) {
+            Some(h) => h,
+            None => return,
+        };
+        self.total.sub(amount_msat);
+        if let Some(channel) = self.channels.get_mut(&key.scid) {
+            channel.sub(amount_msat);
+            if channel.htlcs == 0 {
+                self.channels.remove(&key.scid);
+            }
+        }
+        if let Some(p) = peer_id {
+            if let Some(peer) = self.peers.get_mut(&p) {
+                peer.sub(amount_msat);
+                if peer.htlcs == 0 {
+                    self.peers.remove(&p);
+                }
+            }
+        }
+    }
+
+    pub fn total(&self) -> Tally {
+        self.total
+    }
+}
+
+/// The peer behind the incoming channel `scid`, from `channel_peers` or,
+/// on a miss, from `listpeerchannels`. The channel of an htlc we are
+/// offered never changes its peer, so the cache is never invalidated.
+pub async fn peer_of(plugin: &Plugin<PluginState>, scid: &str) -> Option<String> {
+    if let Some(peer_id) = plugin.state().channel_peers.lock().get(scid) {
+        return Some(peer_id.clone());
+    }
+    let infos = match channel_infos(&plugin.state().rpc).await {
+        Ok(i) => i,
+        Err(e) => {
+            warn!(
+                "Could not resolve peer of `{}`, skipping peer limits: {}",
+                scid, e
+            );
+            return None;
+        }
+    };
+    let mut peers = plugin.state().channel_peers.lock();
+    for (s, info) in infos {
+        peers.insert(s, info.peer_id);
+    }
+    match peers.get(scid) {
+        Some(peer_id) => Some(peer_id.clone()),
+        None => {
+            warn!("Unknown channel `{}`, skipping peer limits", scid);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn htlc(scid: &str, id: u64) -> HtlcKey {
+        HtlcKey {
+            scid: scid.to_string(),
+            id,
+        }
+    }
+
+    #[test]
+    fn reserve_counts_and_release_frees() {
+        let limits = Limits {
+            total_msat: Some(3_000),
+            channel_htlcs: Some(2),
+            peer_htlcs: Some(2),
+            ..Default::default()
+        };
+        let mut held = HeldTotals::default();
+        assert_eq!(
+            held.reserve(&limits, &htlc("1x1x1", 0), Some("a"), 1_000),
+            Ok(())
+        );
+        assert_eq!(
+            held.reserve(&limits, &htlc("1x1x1", 1), Some("a"), 1_000),
+            Ok(())
+        );
+        assert_eq!(
+            held.reserve(&limits, &htlc("1x1x1", 2), Some("b"), 500),
+            Err("channel_htlcs")
+        );
+        assert_eq!(
+            held.reserve(&limits, &htlc("2x2x2", 0), Some("a"), 500),
+            Err("peer_htlcs")
+        );
+        // the same htlc again is not counted twice
+        assert_eq!(
+            held.reserve(&limits, &htlc("1x1x1", 1), Some("a"), 1_000),
+            Ok(())
+        );
+        assert_eq!(
+            held.reserve(&limits, &htlc("3x3x3", 0), None, 1_001),
+            Err("total_msat")
+        );
+        assert_eq!(
+            held.total(),
+            Tally {
+                msat: 2_000,
+                htlcs: 2
+            }
+        );
+
+        held.release(&htlc("1x1x1", 0));
+        held.release(&htlc("1x1x1", 0));
+        assert_eq!(
+            held.reserve(&limits, &htlc("2x2x2", 0), Some("a"), 1_000),
+            Ok(())
+        );
+        assert_eq!(
+            held.total(),
+            Tally {
+                msat: 2_000,
+                htlcs: 2
+            }
+        );
+        held.release(&htlc("1x1x1", 1));
+        held.release(&htlc("2x2x2", 0));
+        assert_eq!(held.total(), Tally::default());
+        assert!(held.channels.is_empty() && held.peers.is_empty());
+    }
+}
@@ -1,20 +1,40 @@
 use anyhow::{anyhow, Context, Result};
 use cln_grpc::pb::node_server::NodeServer;
-use cln_plugin::{options, Builder};
-use cln_rpc::model::ListinvoicesInvoices;
+use cln_plugin::{messages, options, Builder};
 use log::{debug, info, warn};
 use parking_lot::Mutex;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
+mod blocks;
+mod channels;
 mod config;
+mod exposure;
+mod forwards;
+mod gc;
 mod hooks;
+mod invoices;
+mod keysend;
+mod limits;
+mod maintenance;
+mod metrics;
+mod notifications;
+mod offers;
+mod payload;
+mod rpc;
+mod rpcmethods;
+mod shutdown;
 mod state;
+mod stats;
 mod tasks;
 mod tls;
 mod util;
+mod webhooks;
 
 #[derive(Clone, Debug, Copy)]
 pub struct HodlUpdate {
@@ -24,11 +44,26 @@ pub struct HodlUpdate {
 #[derive(Clone, Debug)]
 pub struct PluginState {
     pub config: Arc<Mutex<config::Config>>,
+    /// Highest block seen, see `blocks::Chain::safe_height`.
     pub blockheight: Arc<Mutex<u32>>,
-    pub invoice_amts: Arc<Mutex<BTreeMap<String, u64>>>,
-    pub states: Arc<tokio::sync::Mutex<BTreeMap<String, HodlUpdate>>>,
-    pub invoices: Arc<Mutex<BTreeMap<String, ListinvoicesInvoices>>>,
-    rpc_path: PathBuf,
+    pub chain: Arc<Mutex<blocks::Chain>>,
+    pub channels: Arc<Mutex<channels::ChannelWatch>>,
+    pub states: invoices::HodlInvoices,
+    pub stats: Arc<stats::Stats>,
+    pub metrics: Arc<metrics::Metrics>,
+    pub rpc: rpc::RpcClient,
+    pub webhook_wakeup: Arc<tokio::sync::Notify>,
+    /// What we hold, checked against the `hodl-max-*` limits.
+    pub held: Arc<Mutex<limits::HeldTotals>>,
+    /// Peers of incoming channels, see `limits::peer_of`.
+    pub channel_peers: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Reject new hold payments, see `hodl-maintenance`.
+    pub maintenance: Arc<AtomicBool>,
+    pub shutdown: Arc<shutdown::Shutdown>,
+    /// Offers whose invoices are held, see `hodl-offer`.
+    pub offers: Arc<Mutex<BTreeSet<String>>>,
+    /// Payment hashes whose forwards are held, see `hodl-forward`.
+    pub forwards: Arc<Mutex<BTreeMap<String, forwards::ForwardHold>>>,
     identity: tls::Identity,
     ca_cert: Vec<u8>,
 }
@@ -42,29 +77,241 @@ async fn main() -> Result<()> {
     let directory = std::env::current_dir()?;
     let (identity, ca_cert) = tls::init(&directory)?;
 
-    let state = PluginState {
+    let mut state = PluginState {
         config: Arc::new(Mutex::new(config::Config::new())),
         blockheight: Arc::new(Mutex::new(u32::default())),
-        invoice_amts: Arc::new(Mutex::new(BTreeMap::new())),
-        states: Arc::new(tokio::sync::Mutex::new(BTreeMap::new())),
-        invoices: Arc::new(Mutex::new(BTreeMap::new())),
-        rpc_path: path.into(),
+        chain: Arc::new(Mutex::new(blocks::Chain::default())),
+        channels: Arc::new(Mutex::new(channels::ChannelWatch::default())),
+        states: invoices::HodlInvoices::new(),
+        stats: Arc::new(stats::Stats::default()),
+        metrics: Arc::new(metrics::Metrics::new()?),
+        rpc: rpc::RpcClient::new(path.into()),
+        webhook_wakeup: Arc::new(tokio::sync::Notify::new()),
+        held: Arc::new(Mutex::new(limits::HeldTotals::default())),
+        channel_peers: Arc::new(Mutex::new(BTreeMap::new())),
+        maintenance: Arc::new(AtomicBool::new(false)),
+        shutdown: Arc::new(shutdown::Shutdown::default()),
+        offers: Arc::new(Mutex::new(BTreeSet::new())),
+        forwards: Arc::new(Mutex::new(BTreeMap::new())),
         identity,
         ca_cert,
     };
 
-    let plugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
+    let mut builder = Builder::new(tokio::io::stdin(), tokio::io::stdout());
+    for topic in notifications::TOPICS {
+        builder = builder.notification(messages::NotificationTopic::new(topic));
+    }
+    let plugin = match builder
         .option(options::ConfigOption::new(
             "grpc-hodl-port",
             options::Value::Integer(-1),
             "Which port should the grpc plugin listen for incoming connections?",
         ))
+        .option(options::ConfigOption::new(
+            "hodl-retention",
+            options::Value::Integer(3_600),
+            "Seconds to keep a paid or expired hodl-invoice after it expired",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-archive",
+            options::Value::Boolean(false),
+            "Keep a summary of finished hodl-invoices instead of deleting them",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-settle-deadline",
+            options::Value::Integer(60),
+            "Seconds after settling a hodl-invoice until we warn that lightningd hasn't marked it paid",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-webhook",
+            options::Value::String(String::new()),
+            "Comma separated urls that get hodl state changes POSTed",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-webhook-secret",
+            options::Value::String(String::new()),
+            "HMAC-SHA256 key used to sign webhook requests",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-metrics-port",
+            options::Value::Integer(-1),
+            "Port on localhost to serve Prometheus metrics on, -1 to disable",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-held-msat",
+            options::Value::Integer(-1),
+            "Most msat held at once over all hodl-invoices, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-held-htlcs",
+            options::Value::Integer(-1),
+            "Most htlcs held at once over all hodl-invoices, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-channel-msat",
+            options::Value::Integer(-1),
+            "Most msat held at once per incoming channel, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-channel-htlcs",
+            options::Value::Integer(-1),
+            "Most htlcs held at once per incoming channel, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-peer-msat",
+            options::Value::Integer(-1),
+            "Most msat held at once per peer, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-max-peer-htlcs",
+            options::Value::Integer(-1),
+            "Most htlcs held at once per peer, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-channel-policy",
+            options::Value::String("alert".to_string()),
+            "What to do with holds on a closing channel or from a long offline peer: alert|fail|cancel",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-peer-offline-timeout",
+            options::Value::Integer(600),
+            "Seconds a peer may be offline before hodl-channel-policy applies to its holds",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-peer-offline-blocks",
+            options::Value::Integer(72),
+            "Blocks before the cltv timeout from which hodl-channel-policy applies to holds of offline peers",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-keysend",
+            options::Value::Boolean(false),
+            "Hold keysend payments matching the hodl-keysend-* rules like hodl-invoices",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-keysend-min-msat",
+            options::Value::Integer(-1),
+            "Smallest keysend payment to hold, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-keysend-max-msat",
+            options::Value::Integer(-1),
+            "Largest keysend payment to hold, -1 for no limit",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-keysend-records",
+            options::Value::String(String::new()),
+            "Comma separated TLV types (type or type=hex) a keysend payment must carry to be held",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-keysend-expiry",
+            options::Value::Integer(86_400),
+            "Seconds until a held keysend payment expires and is canceled",
+        ))
+        .option(options::ConfigOption::new(
+            "hodl-forward-min-blocks",
+            options::Value::Integer(18),
+            "Fail a held forward once its outgoing htlc would have fewer blocks than this left",
+        ))
+        .rpcmethod(
+            "hodl-exposure",
+            "Show held msat, htlcs and nearest cltv expiry per incoming channel and peer",
+            rpcmethods::hodl_exposure,
+        )
+        .rpcmethod(
+            "hodl-forward",
+            "Hold forwards of payment_hash, then release them with action continue or fail",
+            rpcmethods::hodl_forward,
+        )
+        .rpcmethod(
+            "hodl-gc",
+            "Find orphaned or malformed hodl datastore entries, delete them if dry_run=false",
+            rpcmethods::hodl_gc,
+        )
+        .rpcmethod(
+            "hodl-maintenance",
+            "Show maintenance mode or turn it on or off, new hold payments are rejected while it is on",
+            rpcmethods::hodl_maintenance,
+        )
+        .rpcmethod(
+            "hodl-list",
+            "List hodl-invoices and their state, only those of offer_id if given",
+            rpcmethods::hodl_list,
+        )
+        .rpcmethod(
+            "hodl-lookup",
+            "Show state and outcome of a hodl-invoice",
+            rpcmethods::hodl_lookup,
+        )
+        .rpcmethod(
+            "hodl-offer",
+            "Hold all invoices of a BOLT12 offer, hold=false to stop, list held offers without offer_id",
+            rpcmethods::hodl_offer,
+        )
+        .rpcmethod(
+            "hodl-release-all",
+            "Cancel or settle all held hodl-invoices or fail htlcs near their cltv expiry, mode: cancel|settle|deadline",
+            rpcmethods::hodl_release_all,
+        )
+        .rpcmethod(
+            "hodl-purge",
+            "Delete all stored data of a hodl-invoice, including its archive",
+            rpcmethods::hodl_purge,
+        )
+        .rpcmethod(
+            "hodl-stats",
+            "Show hodl plugin counters",
+            rpcmethods::hodl_stats,
+        )
         .hook("htlc_accepted", hooks::htlc_handler)
         .subscribe("block_added", hooks::block_added)
+        .subscribe("invoice_payment", hooks::invoice_payment)
+        .subscribe("channel_state_changed", channels::channel_state_changed)
+        .subscribe("connect", channels::connect)
+        .subscribe("disconnect", channels::disconnect)
+        .subscribe("shutdown", shutdown::shutdown)
         .configure()
         .await?
     {
         Some(p) => {
+            state.rpc = rpc::RpcClient::new(util::make_rpc_path(&p.configuration()));
+            match state::migrate_datastore_states(&state.rpc).await {
+                Ok(0) => (),
+                Ok(moved) => info!(
+                    "Moved {} hodl-invoice states to `{}`",
+                    moved,
+                    state::HODLVOICE_STATE_NAME
+                ),
+                Err(e) => warn!("Could not move hodl-invoice states: {}", e),
+            }
+            match maintenance::load_maintenance(&state.rpc).await {
+                Ok(true) => {
+                    warn!("Maintenance mode is ON, rejecting new hold payments");
+                    state.maintenance.store(true, Ordering::Relaxed);
+                }
+                Ok(false) => (),
+                Err(e) => warn!("Could not read maintenance mode: {}", e),
+            }
+            // replayed htlcs arrive before the first `block_added`
+            match util::getinfo(&state.rpc).await {
+                Ok(info) => {
+                    state.chain.lock().start_at(info.blockheight);
+                    *state.blockheight.lock() = state.chain.lock().safe_height();
+                }
+                Err(e) => warn!("Could not read the blockheight: {}", e),
+            }
+            shutdown::load_held(&state.rpc).await;
+            match channels::load_channel_watch(&state.rpc).await {
+                Ok(w) => *state.channels.lock() = w,
+                Err(e) => warn!("Could not read offline peers: {}", e),
+            }
+            match offers::load_offers(&state.rpc).await {
+                Ok(o) => *state.offers.lock() = o,
+                Err(e) => warn!("Could not read held offers: {}", e),
+            }
+            match forwards::load_forwards(&state.rpc).await {
+                Ok(f) => *state.forwards.lock() = f,
+                Err(e) => warn!("Could not read forward holds: {}", e),
+            }
             info!("read config");
             match config::read_config(&p, state.clone()).await {
                 Ok(()) => &(),
@@ -92,6 +339,23 @@ async fn main() -> Result<()> {
                     Err(e) => warn!("Error in clean_up thread: {}", e.to_string()),
                 };
             });
+            let webhook_state = p.clone();
+            tokio::spawn(async move {
+                match webhooks::deliver(webhook_state.clone()).await {
+                    Ok(()) => (),
+                    Err(e) => warn!("Error in webhook thread: {}", e),
+                };
+            });
+            let metrics_port = p.state().config.lock().metrics_port.1;
+            if let Some(port) = metrics_port {
+                let metrics_state = p.clone();
+                tokio::spawn(async move {
+                    match metrics::serve(metrics_state, port).await {
+                        Ok(()) => (),
+                        Err(e) => warn!("Error in metrics thread: {}", e),
+                    };
+                });
+            }
             let plugin_state = p.clone();
             tokio::select! {
                 _ = plugin_state.join() => {
@@ -100,6 +364,9 @@ async fn main() -> Result<()> {
                 // messages anymore.
                     debug!("Plugin loop terminated")
                 }
+                _ = plugin_state.state().shutdown.done.notified() => {
+                    info!("Shutdown complete, exiting")
+                }
             }
             Ok(())
         }
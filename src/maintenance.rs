@@ -0,0 +1,284 @@
+use std::{
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::{info, warn};
+use serde_json::json;
+use tokio::time;
+
+use crate::{
+    forwards::set_forward,
+    notifications,
+    rpc::RpcClient,
+    state::{
+        datastore_maintenance, datastore_update_state, del_datastore_maintenance,
+        list_datastore_maintenance, list_datastore_state, DatastoreError, HodlState,
+    },
+    HodlUpdate, PluginState,
+};
+
+const MAX_RELEASE_ATTEMPTS: u32 = 3;
+const DEADLINE_RELEASE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How `hodl-release-all` stops holding funds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReleaseMode {
+    /// Cancel every open or accepted hodl-invoice.
+    Cancel,
+    /// Settle every accepted hodl-invoice, lightningd knows the preimages.
+    Settle,
+    /// Only fail the htlcs expiring within the given number of blocks.
+    Deadline,
+}
+impl ReleaseMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReleaseMode::Cancel => "cancel",
+            ReleaseMode::Settle => "settle",
+            ReleaseMode::Deadline => "deadline",
+        }
+    }
+}
+impl FromStr for ReleaseMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ReleaseMode, Error> {
+        match s.to_lowercase().as_str() {
+            "cancel" => Ok(ReleaseMode::Cancel),
+            "settle" => Ok(ReleaseMode::Settle),
+            "deadline" => Ok(ReleaseMode::Deadline),
+            _ => Err(anyhow!("mode must be one of cancel, settle or deadline")),
+        }
+    }
+}
+
+/// Whether maintenance mode was left on before we started.
+pub async fn load_maintenance(rpc: &RpcClient) -> Result<bool, Error> {
+    Ok(list_datastore_maintenance(rpc).await?.is_some())
+}
+
+/// Turn maintenance mode on or off. It is kept in the datastore so a
+/// restart doesn't silently start holding payments again.
+pub async fn set_maintenance(plugin: &Plugin<PluginState>, on: bool) -> Result<(), Error> {
+    let rpc = &plugin.state().rpc;
+    if on {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        datastore_maintenance(rpc, json!({ "since": since }).to_string()).await?;
+    } else if list_datastore_maintenance(rpc).await?.is_some() {
+        del_datastore_maintenance(rpc).await?;
+    }
+    if plugin.state().maintenance.swap(on, Ordering::Relaxed) != on {
+        warn!(
+            "Maintenance mode {}",
+            if on {
+                "ON, rejecting new hold payments"
+            } else {
+                "OFF"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Move `pay_hash` to `new_state` unless that's no valid transition
+/// anymore. Returns the old state if we changed it.
+pub async fn move_state(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    new_state: HodlState,
+) -> Result<Option<HodlState>, Error> {
+    let rpc = &plugin.state().rpc;
+    let _write = plugin.state().shutdown.write();
+    for _attempt in 0..MAX_RELEASE_ATTEMPTS {
+        let data = list_datastore_state(rpc, pay_hash.to_string()).await?;
+        let old = HodlState::from_str(&data.string.unwrap_or_default())?;
+        if old == new_state || !old.is_valid_transition(&new_state) {
+            return Ok(None);
+        }
+        let generation = data.generation.unwrap_or(0);
+        match datastore_update_state(rpc, pay_hash.to_string(), new_state.to_string(), generation)
+            .await
+        {
+            Ok(o) => {
+                plugin
+                    .state()
+                    .states
+                    .update(
+                        pay_hash,
+                        HodlUpdate {
+                            state: new_state,
+                            generation: o.generation.unwrap_or(generation + 1),
+                        },
+                    )
+                    .await;
+                notifications::state_changed(plugin, pay_hash, Some(old), new_state).await;
+                return Ok(Some(old));
+            }
+            Err(DatastoreError::WrongGeneration) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(anyhow!(
+        "payment_hash: `{}`. State kept changing, gave up after {} attempts",
+        pay_hash,
+        MAX_RELEASE_ATTEMPTS
+    ))
+}
+
+/// Htlcs that got a release request and were not failed yet.
+async fn pending_releases(plugin: &Plugin<PluginState>) -> usize {
+    let mut pending = 0;
+    for pay_hash in plugin.state().states.pay_hashes() {
+        if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+            pending += entry
+                .htlcs()
+                .values()
+                .filter(|h| h.release_requested)
+                .count();
+        }
+    }
+    pending
+        + plugin
+            .state()
+            .forwards
+            .lock()
+            .values()
+            .flat_map(|f| f.htlcs.values())
+            .filter(|h| h.release_requested)
+            .count()
+}
+
+/// Stop holding funds for every hodl-invoice we track, see `ReleaseMode`.
+pub async fn release_all(
+    plugin: &Plugin<PluginState>,
+    mode: ReleaseMode,
+    blocks: u32,
+) -> Result<serde_json::Value, Error> {
+    let new_state = match mode {
+        ReleaseMode::Cancel => HodlState::Canceled,
+        ReleaseMode::Settle => HodlState::Settled,
+        ReleaseMode::Deadline => {
+            let before = *plugin.state().blockheight.lock() + blocks;
+            let mut htlcs = 0;
+            for pay_hash in plugin.state().states.pay_hashes() {
+                htlcs += plugin
+                    .state()
+                    .states
+                    .with_entry(&pay_hash, |e| e.request_release(before))
+                    .await
+                    .unwrap_or(0);
+            }
+            for hold in plugin.state().forwards.lock().values_mut() {
+                htlcs += hold.request_release(before);
+            }
+            info!(
+                "Release requested for {} htlcs expiring at or before block {}",
+                htlcs, before
+            );
+            // give the htlc handlers time to fail them, so it is safe to
+            // stop lightningd once this returns with nothing pending
+            let _res = time::timeout(DEADLINE_RELEASE_TIMEOUT, async {
+                while pending_releases(plugin).await > 0 {
+                    time::sleep(DEADLINE_POLL_INTERVAL).await;
+                }
+            })
+            .await;
+            return Ok(json!({
+                "mode": mode.as_str(),
+                "cltv_expiry": before,
+                "htlcs": htlcs,
+                "pending": pending_releases(plugin).await,
+            }));
+        }
+    };
+
+    let mut changed = Vec::new();
+    let mut errors = Vec::new();
+    for pay_hash in plugin.state().states.pay_hashes() {
+        let state = match plugin.state().states.entry(&pay_hash).await {
+            Some(e) => e.state,
+            None => continue,
+        };
+        let wanted = match mode {
+            ReleaseMode::Settle => state == HodlState::Accepted,
+            _ => matches!(state, HodlState::Open | HodlState::Accepted),
+        };
+        if !wanted {
+            continue;
+        }
+        match move_state(plugin, &pay_hash, new_state).await {
+            Ok(Some(old)) => {
+                info!(
+                    "payment_hash: `{}`. Released by hodl-release-all. State={}",
+                    pay_hash,
+                    new_state.to_string().to_uppercase()
+                );
+                changed.push(json!({
+                    "payment_hash": pay_hash,
+                    "old_state": old.to_string(),
+                    "new_state": new_state.to_string(),
+                }));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                warn!("payment_hash: `{}`. Could not release: {}", pay_hash, e);
+                errors.push(json!({
+                    "payment_hash": pay_hash,
+                    "error": e.to_string(),
+                }));
+            }
+        }
+    }
+
+    let mut forwards = Vec::new();
+    let held_forwards: Vec<(String, HodlState)> = plugin
+        .state()
+        .forwards
+        .lock()
+        .iter()
+        .map(|(pay_hash, hold)| (pay_hash.clone(), hold.update.state))
+        .collect();
+    for (pay_hash, state) in held_forwards {
+        let (wanted, action) = match mode {
+            ReleaseMode::Settle => (state == HodlState::Accepted, "continue"),
+            _ => (
+                matches!(state, HodlState::Open | HodlState::Accepted),
+                "fail",
+            ),
+        };
+        if !wanted {
+            continue;
+        }
+        match set_forward(plugin, &pay_hash, action).await {
+            Ok(new_state) => forwards.push(json!({
+                "payment_hash": pay_hash,
+                "old_state": state.to_string(),
+                "new_state": new_state.to_string(),
+            })),
+            Err(e) => {
+                warn!(
+                    "payment_hash: `{}`. Could not release forward: {}",
+                    pay_hash, e
+                );
+                errors.push(json!({
+                    "payment_hash": pay_hash,
+                    "error": e.to_string(),
+                }));
+            }
+        }
+    }
+    Ok(json!({
+        "mode": mode.as_str(),
+        "invoices": changed,
+        "forwards": forwards,
+        "errors": errors,
+    }))
+}
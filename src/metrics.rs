@@ -0,0 +1,244 @@
+use std::{convert::Infallible, net::SocketAddr, sync::atomic::Ordering};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::{
+    state::{list_datastore_states, HodlState},
+    PluginState,
+};
+
+const HOLD_DURATION_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3_600.0, 21_600.0, 86_400.0,
+];
+
+/// Prometheus metrics served on `hodl-metrics-port`. Gauges and counters
+/// are filled from `PluginState` when scraped, histograms are observed
+/// where things happen.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    invoices: IntGaugeVec,
+    held_htlcs: IntGauge,
+    held_msat: IntGauge,
+    pub hold_duration: Histogram,
+    pub lookup_state_duration: Histogram,
+    pub clean_up_duration: Histogram,
+    datastore_conflicts: IntCounter,
+    auto_cancels: IntCounter,
+    cltv_timeouts: IntCounter,
+    maintenance_rejections: IntCounter,
+    pub limit_rejections: IntCounterVec,
+}
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some("hodl".to_string()), None)?;
+        let metrics = Metrics {
+            invoices: IntGaugeVec::new(
+                Opts::new("invoices", "Hodl-invoices in the datastore by state"),
+                &["state"],
+            )?,
+            held_htlcs: IntGauge::new(
+                "held_htlcs",
+                "Htlcs currently held, for hodl-invoices and forward holds",
+            )?,
+            held_msat: IntGauge::new(
+                "held_msat",
+                "Msat currently held, for hodl-invoices and forward holds",
+            )?,
+            hold_duration: Histogram::with_opts(
+                HistogramOpts::new("hold_duration_seconds", "How long htlcs were held")
+                    .buckets(HOLD_DURATION_BUCKETS.to_vec()),
+            )?,
+            lookup_state_duration: Histogram::with_opts(HistogramOpts::new(
+                "lookup_state_seconds",
+                "Duration of one lookup_state run",
+            ))?,
+            clean_up_duration: Histogram::with_opts(HistogramOpts::new(
+                "clean_up_seconds",
+                "Duration of one clean_up run",
+            ))?,
+            datastore_conflicts: IntCounter::new(
+                "datastore_conflicts_total",
+                "State writes that lost against a newer generation",
+            )?,
+            auto_cancels: IntCounter::new(
+                "auto_cancels_total",
+                "Hodl-invoices canceled by the plugin itself rather than the operator",
+            )?,
+            cltv_timeouts: IntCounter::new(
+                "cltv_timeouts_total",
+                "Htlcs failed because their cltv expiry got too close",
+            )?,
+            maintenance_rejections: IntCounter::new(
+                "maintenance_rejections_total",
+                "Htlcs failed because maintenance mode is on",
+            )?,
+            limit_rejections: IntCounterVec::new(
+                Opts::new(
+                    "limit_rejections_total",
+                    "Htlcs failed because holding them would break a limit, by limit",
+                ),
+                &["limit"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.invoices.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.held_htlcs.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.held_msat.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hold_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.lookup_state_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.clean_up_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.datastore_conflicts.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.auto_cancels.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.cltv_timeouts.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.maintenance_rejections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.limit_rejections.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Counters mirror the `Stats` atomics, which only ever grow.
+fn sync_counter(counter: &IntCounter, value: u64) {
+    if value > counter.get() {
+        counter.inc_by(value - counter.get());
+    }
+}
+
+async fn render(plugin: &Plugin<PluginState>) -> Result<String, Error> {
+    let metrics = &plugin.state().metrics;
+    let stats = &plugin.state().stats;
+
+    for state in [
+        HodlState::Open,
+        HodlState::Accepted,
+        HodlState::Settled,
+        HodlState::Canceled,
+    ] {
+        metrics
+            .invoices
+            .with_label_values(&[&state.to_string()])
+            .set(0);
+    }
+    // we only track the hodl-invoices that got an htlc since we started
+    for update in list_datastore_states(&plugin.state().rpc).await?.values() {
+        metrics
+            .invoices
+            .with_label_values(&[&update.state.to_string()])
+            .inc();
+    }
+    let mut held_htlcs = 0;
+    let mut held_msat = 0;
+    for pay_hash in plugin.state().states.pay_hashes() {
+        if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+            held_htlcs += entry.htlcs().len() as i64;
+            held_msat += entry.held_msat() as i64;
+        }
+    }
+    for htlc in plugin
+        .state()
+        .forwards
+        .lock()
+        .values()
+        .flat_map(|f| f.htlcs.values())
+    {
+        held_htlcs += 1;
+        held_msat += htlc.amount_msat as i64;
+    }
+    metrics.held_htlcs.set(held_htlcs);
+    metrics.held_msat.set(held_msat);
+
+    sync_counter(
+        &metrics.datastore_conflicts,
+        stats.datastore_conflicts.load(Ordering::Relaxed),
+    );
+    sync_counter(
+        &metrics.auto_cancels,
+        stats.auto_cancels.load(Ordering::Relaxed),
+    );
+    sync_counter(
+        &metrics.cltv_timeouts,
+        stats.cltv_timeouts.load(Ordering::Relaxed),
+    );
+    sync_counter(
+        &metrics.maintenance_rejections,
+        stats.maintenance_rejections.load(Ordering::Relaxed),
+    );
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+async fn handle(plugin: Plugin<PluginState>, req: Request<Body>) -> Response<Body> {
+    if req.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("not found"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    match render(&plugin).await {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                TextEncoder::new().format_type().parse().unwrap(),
+            );
+            response
+        }
+        Err(e) => {
+            warn!("Error rendering metrics: {}", e);
+            let mut response = Response::new(Body::from(e.to_string()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// Serve `/metrics` on localhost only.
+pub async fn serve(plugin: Plugin<PluginState>, port: u16) -> Result<(), Error> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    info!("Starting metrics endpoint on http://{}/metrics", addr);
+    let make_service = make_service_fn(move |_conn| {
+        let plugin = plugin.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let plugin = plugin.clone();
+                async move { Ok::<_, Infallible>(handle(plugin, req).await) }
+            }))
+        }
+    });
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
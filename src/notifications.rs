@@ -0,0 +1,97 @@
+use cln_plugin::Plugin;
+use log::warn;
+use serde_json::json;
+
+use crate::{invoices::HtlcKey, state::HodlState, webhooks, PluginState};
+
+pub const HODL_STATE_CHANGED: &str = "hodl_state_changed";
+pub const HODL_HTLC_HELD: &str = "hodl_htlc_held";
+pub const HODL_HTLC_RELEASED: &str = "hodl_htlc_released";
+
+/// Custom notification topics we declare to lightningd.
+pub const TOPICS: [&str; 3] = [HODL_STATE_CHANGED, HODL_HTLC_HELD, HODL_HTLC_RELEASED];
+
+/// What we told lightningd to do with a held htlc.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtlcResult {
+    Settled,
+    Failed,
+}
+impl HtlcResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HtlcResult::Settled => "settled",
+            HtlcResult::Failed => "failed",
+        }
+    }
+}
+
+async fn send(plugin: &Plugin<PluginState>, topic: &str, payload: serde_json::Value) {
+    if let Err(e) = plugin
+        .send_custom_notification(topic.to_string(), payload)
+        .await
+    {
+        warn!("Could not send {} notification: {}", topic, e);
+    }
+}
+
+/// `old` is `None` if we never saw the hodl-invoice before.
+pub async fn state_changed(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    old: Option<HodlState>,
+    new: HodlState,
+) {
+    let entry = plugin.state().states.entry(pay_hash).await;
+    let payload = json!({
+        "payment_hash": pay_hash,
+        "old_state": old.map(|s| s.to_string()),
+        "new_state": new.to_string(),
+        "outcome": entry.as_ref().and_then(|e| e.outcome()),
+        "reason": entry.as_ref().and_then(|e| e.reason).map(|r| r.to_string()),
+        "amount_msat": entry.as_ref().map(|e| e.amount_msat()),
+        "held_msat": entry.as_ref().map(|e| e.held_msat()),
+    });
+    send(plugin, HODL_STATE_CHANGED, payload.clone()).await;
+    webhooks::enqueue(plugin, HODL_STATE_CHANGED, payload).await;
+}
+
+pub async fn htlc_held(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    htlc_key: &HtlcKey,
+    amount_msat: u64,
+) {
+    let entry = plugin.state().states.entry(pay_hash).await;
+    let payload = json!({
+        "payment_hash": pay_hash,
+        "short_channel_id": htlc_key.scid,
+        "htlc_id": htlc_key.id,
+        "amount_msat": amount_msat,
+        "state": entry.as_ref().map(|e| e.state.to_string()),
+        "held_msat": entry.as_ref().map(|e| e.held_msat()),
+        "invoice_amount_msat": entry.as_ref().map(|e| e.amount_msat()),
+    });
+    send(plugin, HODL_HTLC_HELD, payload).await;
+}
+
+pub async fn htlc_released(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    htlc_key: &HtlcKey,
+    amount_msat: Option<u64>,
+    result: HtlcResult,
+) {
+    let entry = plugin.state().states.entry(pay_hash).await;
+    let payload = json!({
+        "payment_hash": pay_hash,
+        "short_channel_id": htlc_key.scid,
+        "htlc_id": htlc_key.id,
+        "amount_msat": amount_msat,
+        "result": result.as_str(),
+        "state": entry.as_ref().map(|e| e.state.to_string()),
+        "reason": entry.as_ref().and_then(|e| e.reason).map(|r| r.to_string()),
+        "held_msat": entry.as_ref().map(|e| e.held_msat()),
+    });
+    send(plugin, HODL_HTLC_RELEASED, payload).await;
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::BTreeSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::info;
+use serde_json::json;
+
+use crate::{
+    payload::HtlcAccepted,
+    rpc::RpcClient,
+    state::{
+        datastore_new_state, datastore_offer, del_datastore_offer, list_datastore_offers,
+        list_datastore_state, HodlState,
+    },
+    util::listinvoices,
+    PluginState,
+};
+
+/// The offers registered with `hodl-offer` before we started.
+pub async fn load_offers(rpc: &RpcClient) -> Result<BTreeSet<String>, Error> {
+    Ok(list_datastore_offers(rpc).await?.into_keys().collect())
+}
+
+/// Hold every invoice lightningd creates for `offer_id` from now on, or
+/// stop doing so. Invoices already held stay held.
+pub async fn set_offer(
+    plugin: &Plugin<PluginState>,
+    offer_id: &str,
+    hold: bool,
+) -> Result<(), Error> {
+    if offer_id.len() != 64 || !offer_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("offer_id must be 64 hex characters"));
+    }
+    let offer_id = offer_id.to_lowercase();
+    let rpc = &plugin.state().rpc;
+    if hold {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        datastore_offer(rpc, offer_id.clone(), json!({ "since": since }).to_string()).await?;
+        plugin.state().offers.lock().insert(offer_id.clone());
+        info!("Holding invoices of offer `{}`", offer_id);
+    } else {
+        // the datastore may have it even if we don't, e.g. after a failed
+        // `hold` or a write by someone else
+        let known = plugin.state().offers.lock().remove(&offer_id);
+        match del_datastore_offer(rpc, offer_id.clone()).await {
+            Ok(_o) => info!("No longer holding new invoices of offer `{}`", offer_id),
+            Err(e) if known => return Err(e),
+            Err(_e) => (),
+        }
+    }
+    Ok(())
+}
+
+/// An htlc arrived for an invoice we don't track. If lightningd created
+/// that invoice for an offer registered with `hodl-offer`, it becomes an
+/// `open` hodl-invoice right away.
+pub async fn register_offer_invoice(
+    plugin: &Plugin<PluginState>,
+    payload: &HtlcAccepted,
+) -> Result<(), Error> {
+    if plugin.state().offers.lock().is_empty() {
+        return Ok(());
+    }
+    // not for us to claim, we would forward it
+    if payload.onion.short_channel_id.is_some() || payload.onion.next_onion.is_some() {
+        return Ok(());
+    }
+    let pay_hash = payload.htlc.payment_hash.as_str();
+    let rpc = &plugin.state().rpc;
+    let invoice = match listinvoices(rpc, None, Some(pay_hash.to_string()), None)
+        .await?
+        .invoices
+        .into_iter()
+        .next()
+    {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    let offer_id = match invoice.local_offer_id {
+        Some(o) => o.to_string(),
+        None => return Ok(()),
+    };
+    if !plugin.state().offers.lock().contains(&offer_id) {
+        return Ok(());
+    }
+    if list_datastore_state(rpc, pay_hash.to_string())
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+    if let Err(e) =
+        datastore_new_state(rpc, pay_hash.to_string(), HodlState::Open.to_string()).await
+    {
+        // another part of the payment may have been first
+        if list_datastore_state(rpc, pay_hash.to_string())
+            .await
+            .is_err()
+        {
+            return Err(e.into());
+        }
+    }
+    info!(
+        "payment_hash: `{}`. Invoice `{}` of offer `{}` is held. State=OPEN",
+        pay_hash, invoice.label, offer_id
+    );
+    Ok(())
+}
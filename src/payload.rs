@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Error};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+/// TLV types from here on are for the application, not for lightning.
+const CUSTOM_RECORD_MIN_TYPE: u64 = 65_536;
+const TLV_PAYMENT_DATA: u64 = 8;
+const TLV_PAYMENT_METADATA: u64 = 16;
+
+/// The `htlc_accepted` hook payload. Fields that older or newer
+/// lightningd versions may leave out are optional.
+#[derive(Clone, Debug, Deserialize)]
+pub struct HtlcAccepted {
+    #[serde(default)]
+    pub onion: Onion,
+    pub htlc: Htlc,
+    #[serde(default)]
+    pub forward_to: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Htlc {
+    pub short_channel_id: String,
+    pub id: u64,
+    #[serde(alias = "amount", deserialize_with = "msat")]
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    #[serde(default)]
+    pub cltv_expiry_relative: Option<i64>,
+    pub payment_hash: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Onion {
+    #[serde(default)]
+    pub payload: Option<String>,
+    #[serde(default, rename = "type")]
+    pub payload_type: Option<String>,
+    #[serde(default)]
+    pub short_channel_id: Option<String>,
+    #[serde(default, alias = "forward_amount", deserialize_with = "opt_msat")]
+    pub forward_msat: Option<u64>,
+    #[serde(default)]
+    pub outgoing_cltv_value: Option<u32>,
+    #[serde(default)]
+    pub shared_secret: Option<String>,
+    #[serde(default)]
+    pub next_onion: Option<String>,
+    #[serde(default)]
+    pub payment_secret: Option<String>,
+    #[serde(default, deserialize_with = "opt_msat")]
+    pub total_msat: Option<u64>,
+    #[serde(default)]
+    pub payment_metadata: Option<String>,
+}
+
+/// What the sender told us in the onion of one htlc, kept so settlement
+/// decisions can look at it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HtlcOnion {
+    pub payment_secret: Option<String>,
+    pub total_msat: Option<u64>,
+    pub payment_metadata: Option<String>,
+    /// Hex values of TLV records with a type of at least 65536.
+    pub custom_records: BTreeMap<u64, String>,
+}
+
+impl Onion {
+    /// Combine the fields lightningd decoded for us with what we find in
+    /// the raw TLV payload.
+    pub fn details(&self) -> Result<HtlcOnion, Error> {
+        let mut details = HtlcOnion {
+            payment_secret: self.payment_secret.clone(),
+            total_msat: self.total_msat,
+            payment_metadata: self.payment_metadata.clone(),
+            custom_records: BTreeMap::new(),
+        };
+        let payload = match self.payload.as_deref() {
+            Some(p) if !p.is_empty() && self.payload_type.as_deref() != Some("legacy") => p,
+            _ => return Ok(details),
+        };
+        for (tlv_type, value) in parse_tlv_stream(&hex::decode(payload)?)? {
+            match tlv_type {
+                TLV_PAYMENT_DATA if value.len() >= 32 => {
+                    details
+                        .payment_secret
+                        .get_or_insert_with(|| hex::encode(&value[..32]));
+                    if details.total_msat.is_none() {
+                        details.total_msat = Some(read_tu64(&value[32..])?);
+                    }
+                }
+                TLV_PAYMENT_METADATA => {
+                    details
+                        .payment_metadata
+                        .get_or_insert_with(|| hex::encode(&value));
+                }
+                t if t >= CUSTOM_RECORD_MIN_TYPE => {
+                    details.custom_records.insert(t, hex::encode(&value));
+                }
+                _ => (),
+            }
+        }
+        Ok(details)
+    }
+}
+
+fn read_bigsize(bytes: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let first = *bytes
+        .get(*pos)
+        .ok_or_else(|| anyhow!("truncated bigsize"))?;
+    let width = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => {
+            *pos += 1;
+            return Ok(n as u64);
+        }
+    };
+    let raw = bytes
+        .get(*pos + 1..*pos + 1 + width)
+        .ok_or_else(|| anyhow!("truncated bigsize"))?;
+    *pos += 1 + width;
+    Ok(raw.iter().fold(0u64, |n, b| n << 8 | *b as u64))
+}
+
+fn read_tu64(bytes: &[u8]) -> Result<u64, Error> {
+    if bytes.len() > 8 {
+        return Err(anyhow!("tu64 longer than 8 bytes"));
+    }
+    Ok(bytes.iter().fold(0u64, |n, b| n << 8 | *b as u64))
+}
+
+/// Split a TLV stream into its records. lightningd may or may not hand us
+/// the payload with its length prefix, so a leading length that covers
+/// exactly the rest is skipped.
+fn parse_tlv_stream(bytes: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    let mut pos = 0;
+    if let Ok(len) = read_bigsize(bytes, &mut pos) {
+        if len as usize != bytes.len() - pos {
+            pos = 0;
+        }
+    }
+    let mut records = Vec::new();
+    while pos < bytes.len() {
+        let tlv_type = read_bigsize(bytes, &mut pos)?;
+        let len = read_bigsize(bytes, &mut pos)? as usize;
+        let value = pos
+            .checked_add(len)
+            .and_then(|end| bytes.get(pos..end))
+            .ok_or_else(|| anyhow!("tlv record {} is truncated", tlv_type))?;
+        records.push((tlv_type, value.to_vec()));
+        pos += len;
+    }
+    Ok(records)
+}
+
+/// Amounts come as `1000` or, from older lightningd, as `"1000msat"`.
+fn parse_msat(value: &serde_json::Value) -> Result<u64, String> {
+    match value {
+        serde_json::Value::Number(n) => n
+            .as_u64()
+            .ok_or_else(|| format!("invalid msat amount `{}`", n)),
+        serde_json::Value::String(s) => s
+            .strip_suffix("msat")
+            .unwrap_or(s)
+            .parse::<u64>()
+            .map_err(|e| format!("invalid msat amount `{}`: {}", s, e)),
+        other => Err(format!("invalid msat amount `{}`", other)),
+    }
+}
+
+fn msat<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    parse_msat(&serde_json::Value::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn opt_msat<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        Some(v) => parse_msat(&v).map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn accepts_old_and_new_amounts() {
+        let old: HtlcAccepted = serde_json::from_value(json!({
+            "onion": {
+                "payload": "",
+                "type": "tlv",
+                "forward_amount": "1000msat",
+                "outgoing_cltv_value": 800_100,
+                "payment_secret": "00",
+                "total_msat": "3000msat",
+            },
+            "htlc": {
+                "short_channel_id": "1x1x1",
+                "id": 3,
+                "amount": "1000msat",
+                "cltv_expiry": 800_144,
+                "payment_hash": "aa",
+            },
+        }))
+        .unwrap();
+        assert_eq!(old.htlc.amount_msat, 1_000);
+        assert_eq!(old.onion.forward_msat, Some(1_000));
+        assert_eq!(old.onion.total_msat, Some(3_000));
+
+        let new: HtlcAccepted = serde_json::from_value(json!({
+            "onion": { "payload": "", "forward_msat": 1_000 },
+            "htlc": {
+                "short_channel_id": "1x1x1",
+                "id": 3,
+                "amount_msat": 1_000,
+                "cltv_expiry": 800_144,
+                "cltv_expiry_relative": 144,
+                "payment_hash": "aa",
+            },
+        }))
+        .unwrap();
+        assert_eq!(new.htlc.amount_msat, 1_000);
+        assert_eq!(new.onion.total_msat, None);
+
+        assert!(serde_json::from_value::<HtlcAccepted>(json!({
+            "htlc": { "short_channel_id": "1x1x1", "id": 3, "amount_msat": "lots" },
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn custom_records_from_tlv_payload() {
+        // amt_to_forward, outgoing_cltv_value, payment_data, metadata and
+        // the custom records 65537 and 5482373484 (keysend preimage)
+        let stream = concat!(
+            "020203e8",
+            "04030c3500",
+            "0822",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "0bb8",
+            "1002abcd",
+            "fe0001000103010203",
+            "ff0000000146c6616c01ff",
+        );
+        let expected = HtlcOnion {
+            payment_secret: Some("11".repeat(32)),
+            total_msat: Some(3_000),
+            payment_metadata: Some("abcd".to_string()),
+            custom_records: BTreeMap::from([
+                (65_537, "010203".to_string()),
+                (5_482_373_484, "ff".to_string()),
+            ]),
+        };
+        for payload in [
+            stream.to_string(),
+            format!("{:02x}", stream.len() / 2) + stream,
+        ] {
+            let onion = Onion {
+                payload: Some(payload),
+                payload_type: Some("tlv".to_string()),
+                ..Default::default()
+            };
+            assert_eq!(onion.details().unwrap(), expected);
+        }
+
+        let truncated = Onion {
+            payload: Some("fe0001000105aa".to_string()),
+            ..Default::default()
+        };
+        assert!(truncated.details().is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::{ClnRpc, Request, Response, RpcError};
+use log::debug;
+use parking_lot::Mutex;
+use tokio::time;
+
+const RPC_POOL_SIZE: usize = 4;
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum RpcCallError {
+    /// lightningd answered the call with an error.
+    Rpc(RpcError),
+    /// Could not reach lightningd or it did not answer in time.
+    Unavailable(Error),
+}
+impl fmt::Display for RpcCallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcCallError::Rpc(e) => write!(f, "{:?}", e),
+            RpcCallError::Unavailable(e) => write!(f, "lightningd unavailable: {}", e),
+        }
+    }
+}
+impl std::error::Error for RpcCallError {}
+
+/// Small pool of connections to lightningd's RPC socket shared by all
+/// hooks and tasks. Idle connections are reused, broken ones are dropped
+/// and a fresh one is dialed on the next call, so a restarting lightningd
+/// only fails the calls made while it was gone.
+#[derive(Clone)]
+pub struct RpcClient {
+    rpc_path: PathBuf,
+    timeout: Duration,
+    idle: Arc<Mutex<Vec<ClnRpc>>>,
+}
+impl fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("rpc_path", &self.rpc_path)
+            .field("timeout", &self.timeout)
+            .field("idle", &self.idle.lock().len())
+            .finish()
+    }
+}
+impl RpcClient {
+    pub fn new(rpc_path: PathBuf) -> RpcClient {
+        RpcClient {
+            rpc_path,
+            timeout: RPC_TIMEOUT,
+            idle: Arc::new(Mutex::new(Vec::with_capacity(RPC_POOL_SIZE))),
+        }
+    }
+
+    pub async fn call(&self, request: Request) -> Result<Response, RpcCallError> {
+        self.call_with_timeout(request, self.timeout).await
+    }
+
+    pub async fn call_with_timeout(
+        &self,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Response, RpcCallError> {
+        let pooled = self.idle.lock().pop();
+        let mut rpc = match pooled {
+            Some(rpc) => rpc,
+            None => match time::timeout(timeout, ClnRpc::new(&self.rpc_path)).await {
+                Ok(Ok(rpc)) => rpc,
+                Ok(Err(e)) => return Err(RpcCallError::Unavailable(e)),
+                Err(_) => {
+                    return Err(RpcCallError::Unavailable(anyhow!(
+                        "connecting to {:?} timed out",
+                        self.rpc_path
+                    )))
+                }
+            },
+        };
+        match time::timeout(timeout, rpc.call(request)).await {
+            Ok(Ok(response)) => {
+                self.put_back(rpc);
+                Ok(response)
+            }
+            // no code means the transport failed, don't reuse the connection
+            Ok(Err(e)) if e.code.is_none() => {
+                debug!("Dropping rpc connection after error: {:?}", e);
+                Err(RpcCallError::Unavailable(anyhow!("{:?}", e)))
+            }
+            Ok(Err(e)) => {
+                self.put_back(rpc);
+                Err(RpcCallError::Rpc(e))
+            }
+            Err(_) => Err(RpcCallError::Unavailable(anyhow!(
+                "rpc call timed out after {}s",
+                timeout.as_secs()
+            ))),
+        }
+    }
+
+    fn put_back(&self, rpc: ClnRpc) {
+        let mut idle = self.idle.lock();
+        if idle.len() < RPC_POOL_SIZE {
+            idle.push(rpc);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{collections::BTreeMap, str::FromStr, sync::atomic::Ordering};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use log::warn;
+use serde_json::json;
+
+use crate::{
+    exposure::{channel_infos, held_by_channel, Exposure},
+    forwards::{forwards_json, set_forward},
+    gc::collect_garbage,
+    maintenance::{release_all, set_maintenance, ReleaseMode},
+    offers::set_offer,
+    state::{
+        del_datastore_archive, del_datastore_forward, del_datastore_htlc_expiry,
+        del_datastore_onions, del_datastore_paid, del_datastore_reason, del_datastore_state,
+        hodl_outcome, list_datastore_offers, list_datastore_onions, list_datastore_paid,
+        list_datastore_reason, list_datastore_state, list_datastore_states, HodlState,
+    },
+    util::listinvoices,
+    PluginState,
+};
+
+pub async fn hodl_stats(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    Ok(plugin.state().stats.to_json())
+}
+
+const DEFAULT_RELEASE_BLOCKS: u32 = 144;
+
+/// State of a hodl-invoice, including how it ended. Answered from memory
+/// while we hold htlcs for it, from the datastore otherwise.
+pub async fn hodl_lookup(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let pay_hash = payment_hash_arg(&args)?;
+    let rpc = &plugin.state().rpc;
+    if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+        return Ok(json!({
+            "payment_hash": pay_hash,
+            "state": entry.state.to_string(),
+            "outcome": entry.outcome(),
+            "reason": entry.reason.map(|r| r.to_string()),
+            "generation": entry.generation,
+            "amount_msat": entry.amount_msat(),
+            "held_msat": entry.held_msat(),
+            "htlcs": entry.htlcs().len(),
+            "paid": entry.paid.map(|p| p.to_json()),
+            "offer_id": entry.invoice.local_offer_id.map(|o| o.to_string()),
+            "onions": list_datastore_onions(rpc, pay_hash.clone()).await?,
+        }));
+    }
+    let data = list_datastore_state(rpc, pay_hash.clone())
+        .await
+        .map_err(|_e| anyhow!("payment_hash: `{}` is not a hodl-invoice", pay_hash))?;
+    let state = HodlState::from_str(&data.string.unwrap_or_default())?;
+    let reason = list_datastore_reason(rpc, pay_hash.clone()).await?;
+    Ok(json!({
+        "payment_hash": pay_hash,
+        "state": state.to_string(),
+        "outcome": hodl_outcome(state, reason),
+        "reason": reason.map(|r| r.to_string()),
+        "generation": data.generation.unwrap_or(0),
+        "held_msat": 0,
+        "htlcs": 0,
+        "paid": list_datastore_paid(rpc, pay_hash.clone()).await?,
+        "onions": list_datastore_onions(rpc, pay_hash.clone()).await?,
+    }))
+}
+
+/// Hold htlcs we would forward for `payment_hash` (`action=hold`, the
+/// default), then let them `continue` to the next hop or `fail` them.
+/// Lists the forward holds when called without `payment_hash`.
+pub async fn hodl_forward(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    if let Some(pay_hash) = arg(&args, 0, "payment_hash") {
+        let pay_hash = pay_hash
+            .as_str()
+            .ok_or_else(|| anyhow!("payment_hash must be a string"))?;
+        let action = match arg(&args, 1, "action") {
+            None => "hold",
+            Some(a) => a
+                .as_str()
+                .ok_or_else(|| anyhow!("action must be hold, continue or fail"))?,
+        };
+        let state = set_forward(&plugin, pay_hash, action).await?;
+        return Ok(json!({
+            "payment_hash": pay_hash.to_lowercase(),
+            "state": state.to_string(),
+        }));
+    }
+    Ok(forwards_json(&plugin))
+}
+
+/// Hold every invoice lightningd creates for `offer_id`, or stop with
+/// `hold=false`. Lists the held offers when called without `offer_id`.
+pub async fn hodl_offer(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    if let Some(offer_id) = arg(&args, 0, "offer_id") {
+        let offer_id = offer_id
+            .as_str()
+            .ok_or_else(|| anyhow!("offer_id must be a string"))?;
+        let hold = match arg(&args, 1, "hold") {
+            None => true,
+            Some(h) => h
+                .as_bool()
+                .ok_or_else(|| anyhow!("hold must be true or false"))?,
+        };
+        set_offer(&plugin, offer_id, hold).await?;
+    }
+    let offers = list_datastore_offers(&plugin.state().rpc).await?;
+    Ok(json!({
+        "offers": offers
+            .into_iter()
+            .map(|(offer_id, data)| json!({ "offer_id": offer_id, "since": data["since"] }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+/// Payment hash and state of every hodl-invoice, or of those lightningd
+/// created for `offer_id`.
+pub async fn hodl_list(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let rpc = &plugin.state().rpc;
+    let offer_id = match arg(&args, 0, "offer_id") {
+        None => None,
+        Some(o) => Some(
+            o.as_str()
+                .ok_or_else(|| anyhow!("offer_id must be a string"))?
+                .to_string(),
+        ),
+    };
+    let invoices = match offer_id {
+        None => list_datastore_states(rpc)
+            .await?
+            .iter()
+            .map(|(pay_hash, update)| {
+                json!({ "payment_hash": pay_hash, "state": update.state.to_string() })
+            })
+            .collect::<Vec<_>>(),
+        Some(offer_id) => {
+            let invoices = listinvoices(rpc, None, None, Some(offer_id.clone()))
+                .await?
+                .invoices;
+            let states = list_datastore_states(rpc).await?;
+            invoices
+                .into_iter()
+                .filter_map(|i| {
+                    let pay_hash = i.payment_hash.to_string();
+                    states.get(&pay_hash).map(|update| {
+                        json!({
+                            "payment_hash": pay_hash,
+                            "state": update.state.to_string(),
+                            "label": i.label,
+                            "offer_id": offer_id,
+                        })
+                    })
+                })
+                .collect()
+        }
+    };
+    Ok(json!({ "invoices": invoices }))
+}
+
+/// Held msat, htlc count and nearest cltv expiry per incoming channel and
+/// per peer, so we can see which channels our holds put at risk. Held
+/// forwards count as well.
+pub async fn hodl_exposure(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let blockheight = *plugin.state().blockheight.lock();
+    let forwards = plugin.state().forwards.lock().clone();
+    let held = held_by_channel(&plugin.state().states, &forwards).await;
+    let infos = match channel_infos(&plugin.state().rpc).await {
+        Ok(i) => i,
+        Err(e) => {
+            warn!("Could not resolve peers of held htlcs: {}", e);
+            BTreeMap::new()
+        }
+    };
+
+    let mut total = Exposure::default();
+    let mut peers: BTreeMap<String, (Exposure, Vec<String>)> = BTreeMap::new();
+    let mut channels = Vec::new();
+    for (scid, exposure) in held.iter() {
+        total.merge(exposure);
+        let info = infos.get(scid);
+        if let Some(info) = info {
+            let peer = peers.entry(info.peer_id.clone()).or_default();
+            peer.0.merge(exposure);
+            peer.1.push(info.short_channel_id.clone());
+        }
+        let mut channel = exposure.to_json(blockheight);
+        channel["short_channel_id"] = json!(scid);
+        channel["peer_id"] = json!(info.map(|i| i.peer_id.clone()));
+        channel["max_accepted_htlcs"] = json!(info.and_then(|i| i.max_accepted_htlcs));
+        channels.push(channel);
+    }
+    let peers: Vec<serde_json::Value> = peers
+        .into_iter()
+        .map(|(peer_id, (exposure, scids))| {
+            let mut peer = exposure.to_json(blockheight);
+            peer["peer_id"] = json!(peer_id);
+            peer["channels"] = json!(scids);
+            peer
+        })
+        .collect();
+    Ok(json!({
+        "blockheight": blockheight,
+        "total": total.to_json(blockheight),
+        "channels": channels,
+        "peers": peers,
+    }))
+}
+
+/// Delete everything we stored for a payment hash, archived or not.
+pub async fn hodl_purge(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let pay_hash = payment_hash_arg(&args)?;
+    if let Some(entry) = plugin.state().states.entry(&pay_hash).await {
+        if !entry.htlcs().is_empty() {
+            return Err(anyhow!(
+                "payment_hash: `{}` still has {} held htlcs, cancel or settle it first",
+                pay_hash,
+                entry.htlcs().len()
+            ));
+        }
+    }
+    if let Some(hold) = plugin.state().forwards.lock().get(&pay_hash) {
+        if !hold.htlcs.is_empty() {
+            return Err(anyhow!(
+                "payment_hash: `{}` still has {} held forwards, continue or fail them first",
+                pay_hash,
+                hold.htlcs.len()
+            ));
+        }
+    }
+    let rpc = &plugin.state().rpc;
+    let mut deleted = Vec::new();
+    if del_datastore_htlc_expiry(rpc, pay_hash.clone())
+        .await
+        .is_ok()
+    {
+        deleted.push("expiry");
+    }
+    if del_datastore_onions(rpc, pay_hash.clone())
+        .await
+        .is_ok_and(|n| n > 0)
+    {
+        deleted.push("onion");
+    }
+    if del_datastore_state(rpc, pay_hash.clone()).await.is_ok() {
+        deleted.push("state");
+    }
+    if del_datastore_paid(rpc, pay_hash.clone()).await.is_ok() {
+        deleted.push("paid");
+    }
+    if del_datastore_reason(rpc, pay_hash.clone()).await.is_ok() {
+        deleted.push("reason");
+    }
+    if del_datastore_archive(rpc, pay_hash.clone()).await.is_ok() {
+        deleted.push("archive");
+    }
+    if del_datastore_forward(rpc, pay_hash.clone()).await.is_ok() {
+        deleted.push("forward");
+    }
+    plugin.state().forwards.lock().remove(&pay_hash);
+    plugin.state().states.retain(|hash| hash != &pay_hash);
+    Ok(json!({
+        "payment_hash": pay_hash,
+        "deleted": deleted,
+    }))
+}
+
+/// Stop holding funds during an incident: cancel everything, settle what
+/// is accepted or only fail htlcs close to their cltv expiry. Turns on
+/// maintenance mode unless `maintenance` is false.
+pub async fn hodl_release_all(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mode = arg(&args, 0, "mode")
+        .and_then(|m| m.as_str())
+        .ok_or_else(|| anyhow!("missing mode: cancel, settle or deadline"))?;
+    let mode = ReleaseMode::from_str(mode)?;
+    let maintenance = match arg(&args, 1, "maintenance") {
+        None => true,
+        Some(m) => m
+            .as_bool()
+            .ok_or_else(|| anyhow!("maintenance must be true or false"))?,
+    };
+    let blocks = match arg(&args, 2, "blocks") {
+        None => DEFAULT_RELEASE_BLOCKS,
+        Some(b) => b
+            .as_u64()
+            .and_then(|b| u32::try_from(b).ok())
+            .ok_or_else(|| anyhow!("blocks must be a number of blocks"))?,
+    };
+    if maintenance {
+        set_maintenance(&plugin, true).await?;
+    }
+    let mut result = release_all(&plugin, mode, blocks).await?;
+    result["maintenance"] = json!(plugin.state().maintenance.load(Ordering::Relaxed));
+    Ok(result)
+}
+
+/// Show maintenance mode, or turn it on or off with `enable`.
+pub async fn hodl_maintenance(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    if let Some(enable) = arg(&args, 0, "enable") {
+        let enable = enable
+            .as_bool()
+            .ok_or_else(|| anyhow!("enable must be true or false"))?;
+        set_maintenance(&plugin, enable).await?;
+    }
+    Ok(json!({
+        "maintenance": plugin.state().maintenance.load(Ordering::Relaxed),
+    }))
+}
+
+/// Report orphaned or malformed `hodlvoice` entries, delete them unless
+/// `dry_run` (the default) is set.
+pub async fn hodl_gc(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let dry_run = match arg(&args, 0, "dry_run") {
+        None => true,
+        Some(d) => d
+            .as_bool()
+            .ok_or_else(|| anyhow!("dry_run must be true or false"))?,
+    };
+    let (garbage, deleted) = collect_garbage(&plugin, dry_run).await?;
+    Ok(json!({
+        "dry_run": dry_run,
+        "garbage": garbage.iter().map(|g| g.to_json()).collect::<Vec<_>>(),
+        "deleted": deleted,
+    }))
+}
+
+/// Argument `name`, or the one at `position` if called with an array.
+fn arg<'a>(
+    args: &'a serde_json::Value,
+    position: usize,
+    name: &str,
+) -> Option<&'a serde_json::Value> {
+    match args {
+        serde_json::Value::Array(a) => a.get(position),
+        serde_json::Value::Object(o) => o.get(name),
+        _ => None,
+    }
+}
+
+fn payment_hash_arg(args: &serde_json::Value) -> Result<String, Error> {
+    arg(args, 0, "payment_hash")
+        .and_then(|h| h.as_str())
+        .map(|h| h.to_string())
+        .ok_or_else(|| anyhow!("missing payment_hash"))
+}
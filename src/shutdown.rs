@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeSet,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use cln_rpc::model::ListpeerchannelsChannelsHtlcsDirection;
+use log::{info, warn};
+use serde_json::json;
+use tokio::{sync::Notify, time};
+
+use crate::{
+    invoices::HtlcKey,
+    rpc::RpcClient,
+    state::{datastore_held, del_datastore_held, list_datastore_held},
+    util::listpeerchannels,
+    PluginState,
+};
+
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tracks lightningd's `shutdown` notification and the datastore writes
+/// still in flight, so we only exit once they are done.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    writes: AtomicU64,
+    idle: Notify,
+    /// Notified once we are ready to exit.
+    pub done: Notify,
+}
+impl Shutdown {
+    /// Hold the returned guard for the duration of a datastore write.
+    pub fn write(&self) -> WriteGuard<'_> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        WriteGuard(self)
+    }
+
+    /// Wait until no write is in flight, false if `timeout` passed first.
+    async fn writes_flushed(&self, timeout: Duration) -> bool {
+        time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.writes.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+pub struct WriteGuard<'a>(&'a Shutdown);
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.writes.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Log what we held when we last stopped. The records stay: lightningd
+/// replays those htlcs through `htlc_accepted`, which takes them over, and
+/// `forget_gone_held` removes the ones lightningd resolved without us.
+pub async fn load_held(rpc: &RpcClient) {
+    let held = match list_datastore_held(rpc).await {
+        Ok(h) => h,
+        Err(e) => {
+            warn!("Could not read the htlcs held before the restart: {}", e);
+            return;
+        }
+    };
+    if held.is_empty() {
+        return;
+    }
+    info!(
+        "{} htlcs were held when we last stopped, expecting lightningd to replay them",
+        held.len()
+    );
+    for (htlc, record) in held {
+        info!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Held {}msat with cltv_expiry {} before the restart",
+            record["payment_hash"].as_str().unwrap_or_default(),
+            record["short_channel_id"].as_str().unwrap_or_default(),
+            record["id"].as_u64().map_or(htlc, |i| i.to_string()),
+            record["amount_msat"],
+            record["cltv_expiry"],
+        );
+    }
+}
+
+/// Incoming htlcs lightningd still has, as `<scid>-<htlc id>` for the short
+/// channel id and every alias of their channel.
+async fn incoming_htlcs(rpc: &RpcClient) -> Result<BTreeSet<String>, Error> {
+    let mut htlcs = BTreeSet::new();
+    for channel in listpeerchannels(rpc).await?.channels {
+        let mut scids: Vec<String> = channel
+            .short_channel_id
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        if let Some(alias) = channel.alias {
+            scids.extend(
+                [alias.local, alias.remote]
+                    .into_iter()
+                    .flatten()
+                    .map(|a| a.to_string()),
+            );
+        }
+        for htlc in channel.htlcs.unwrap_or_default() {
+            if htlc.direction != ListpeerchannelsChannelsHtlcsDirection::IN {
+                continue;
+            }
+            for scid in scids.iter() {
+                htlcs.insert(format!("{}-{}", scid, htlc.id));
+            }
+        }
+    }
+    Ok(htlcs)
+}
+
+/// Remove the records of held htlcs lightningd doesn't have anymore, e.g.
+/// because their channel closed while we were down.
+pub async fn forget_gone_held(plugin: &Plugin<PluginState>) {
+    let rpc = &plugin.state().rpc;
+    let held = match list_datastore_held(rpc).await {
+        Ok(h) if h.is_empty() => return,
+        Ok(h) => h,
+        Err(e) => {
+            warn!("Could not read the held htlc records: {}", e);
+            return;
+        }
+    };
+    let incoming = match incoming_htlcs(rpc).await {
+        Ok(i) => i,
+        Err(e) => {
+            warn!("Could not check the held htlc records: {}", e);
+            return;
+        }
+    };
+    for htlc in held.into_keys().filter(|h| !incoming.contains(h)) {
+        info!(
+            "Held htlc `{}` is gone from lightningd, removing its record",
+            htlc
+        );
+        let _write = plugin.state().shutdown.write();
+        if let Err(e) = del_datastore_held(rpc, htlc.clone()).await {
+            warn!("Could not remove held htlc record `{}`: {}", htlc, e);
+        }
+    }
+}
+
+/// Write down an htlc we hold, so we know what we held after a crash or
+/// a restart. lightningd doesn't answer our RPC calls anymore once it
+/// sent `shutdown`, so this can't wait until then.
+pub async fn record_held(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    key: &HtlcKey,
+    amount_msat: u64,
+    cltv_expiry: u32,
+) {
+    let record = json!({
+        "payment_hash": pay_hash,
+        "short_channel_id": key.scid,
+        "id": key.id,
+        "amount_msat": amount_msat,
+        "cltv_expiry": cltv_expiry,
+        "held_at": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    });
+    let _write = plugin.state().shutdown.write();
+    if let Err(e) = datastore_held(
+        &plugin.state().rpc,
+        format!("{}-{}", key.scid, key.id),
+        record.to_string(),
+    )
+    .await
+    {
+        warn!(
+            "payment_hash: `{}` scid: `{}` htlc: `{}`. Could not record held htlc: {}",
+            pay_hash, key.scid, key.id, e
+        );
+    }
+}
+
+/// Remove a released htlc from the record `record_held` keeps.
+pub async fn forget_held(plugin: &Plugin<PluginState>, key: &HtlcKey) {
+    let _write = plugin.state().shutdown.write();
+    if let Err(e) =
+        del_datastore_held(&plugin.state().rpc, format!("{}-{}", key.scid, key.id)).await
+    {
+        warn!(
+            "scid: `{}` htlc: `{}`. Could not remove held htlc record: {}",
+            key.scid, key.id, e
+        );
+    }
+}
+
+/// lightningd is shutting down and won't take RPC calls anymore: let the
+/// datastore writes already in flight finish and exit.
+pub async fn shutdown(plugin: Plugin<PluginState>, _v: serde_json::Value) -> Result<(), Error> {
+    let state = plugin.state();
+    if state.shutdown.requested.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    info!("Shutdown requested");
+    if !state.shutdown.writes_flushed(SHUTDOWN_FLUSH_TIMEOUT).await {
+        warn!(
+            "{} datastore writes still pending at shutdown",
+            state.shutdown.writes.load(Ordering::SeqCst)
+        );
+    }
+    state.shutdown.done.notify_one();
+    Ok(())
+}
@@ -1,7 +1,7 @@
 // Huge json!() macros require lots of recursion
 #![recursion_limit = "1024"]
 
-use std::{fmt, path::PathBuf};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 use anyhow::{anyhow, Error};
 use cln_rpc::{
@@ -9,14 +9,63 @@ use cln_rpc::{
         DatastoreMode, DatastoreRequest, DatastoreResponse, DeldatastoreRequest,
         DeldatastoreResponse, ListdatastoreDatastore, ListdatastoreRequest, ListdatastoreResponse,
     },
-    ClnRpc, Request, Response,
+    Request, Response,
 };
-use log::debug;
+use log::{debug, warn};
 
+use crate::{
+    rpc::{RpcCallError, RpcClient},
+    HodlUpdate,
+};
 
 pub const HODLVOICE_PLUGIN_NAME: &str = "hodlvoice";
-const HODLVOICE_DATASTORE_STATE: &str = "state";
-const HODLVOICE_DATASTORE_HTLC_EXPIRY: &str = "expiry";
+/// State of each hodl-invoice, keyed by payment hash. Unlike the other
+/// keys of a hodl-invoice they are not below `hodlvoice/<payment_hash>`,
+/// so one `listdatastore` returns all of them.
+pub const HODLVOICE_STATE_NAME: &str = "hodlvoice-state";
+pub const HODLVOICE_ARCHIVE_NAME: &str = "hodlvoice-archive";
+pub const HODLVOICE_OUTBOX_NAME: &str = "hodlvoice-outbox";
+pub const HODLVOICE_MAINTENANCE_NAME: &str = "hodlvoice-maintenance";
+/// One `<scid>-<htlc id>` key per htlc we hold right now.
+pub const HODLVOICE_HELD_NAME: &str = "hodlvoice-held";
+pub const HODLVOICE_OFFERS_NAME: &str = "hodlvoice-offers";
+/// State of each forward hold, keyed by payment hash.
+pub const HODLVOICE_FORWARDS_NAME: &str = "hodlvoice-forwards";
+pub const HODLVOICE_DATASTORE_STATE: &str = "state";
+pub const HODLVOICE_DATASTORE_HTLC_EXPIRY: &str = "expiry";
+pub const HODLVOICE_DATASTORE_PAID: &str = "paid";
+pub const HODLVOICE_DATASTORE_REASON: &str = "reason";
+/// Parent of one `<scid>-<htlc id>` key per htlc we held.
+pub const HODLVOICE_DATASTORE_ONION: &str = "onion";
+
+// lightningd error codes for `datastore`
+const DATASTORE_UPDATE_DOES_NOT_EXIST: i32 = 1203;
+const DATASTORE_UPDATE_WRONG_GENERATION: i32 = 1204;
+
+/// Why a `datastore` call failed. Callers need to tell an optimistic
+/// concurrency conflict apart from lightningd being unreachable.
+#[derive(Debug)]
+pub enum DatastoreError {
+    /// Someone else wrote the key since we read its generation.
+    WrongGeneration,
+    /// The key we wanted to replace is gone.
+    DoesNotExist,
+    /// Could not talk to lightningd at all, worth retrying.
+    Unavailable(Error),
+    /// lightningd answered with an error that retrying won't fix.
+    Rejected(Error),
+}
+impl fmt::Display for DatastoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatastoreError::WrongGeneration => write!(f, "datastore generation mismatch"),
+            DatastoreError::DoesNotExist => write!(f, "datastore key does not exist"),
+            DatastoreError::Unavailable(e) => write!(f, "lightningd unavailable: {}", e),
+            DatastoreError::Rejected(e) => write!(f, "datastore rejected: {}", e),
+        }
+    }
+}
+impl std::error::Error for DatastoreError {}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum HodlState {
@@ -68,45 +117,6 @@ impl HodlState {
             HodlState::Accepted => true,
         }
     }
-    // pub async fn accepting(&self) -> Result<(), Error> {
-    //     match *self {
-    //         HodlState::Open => Ok(()),
-    //         _ => Err(anyhow!(
-    //             "illegal state transition for accepting: {}",
-    //             *self.to_string()
-    //         )),
-    //     }
-    // }
-
-    // pub async fn unaccepting(&self) -> Result<(), Error> {
-    //     match *self {
-    //         HodlState::Accepted => Ok(()),
-    //         _ => Err(anyhow!(
-    //             "illegal state transition for unaccepting: {}",
-    //             *self.to_string()
-    //         )),
-    //     }
-    // }
-
-    // pub async fn canceling(&self) -> Result<(), Error> {
-    //     match *self {
-    //         HodlState::Open | HodlState::Accepted => Ok(()),
-    //         _ => Err(anyhow!(
-    //             "illegal state transition for canceling: {}",
-    //             *self.to_string()
-    //         )),
-    //     }
-    // }
-
-    // pub async fn settling(&self) -> Result<(), Error> {
-    //     match *self {
-    //         HodlState::Accepted => Ok(()),
-    //         _ => Err(anyhow!(
-    //             "illegal state transition for settling: {}",
-    //             *self.to_string()
-    //         )),
-    //     }
-    // }
 }
 impl fmt::Display for HodlState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -119,15 +129,75 @@ impl fmt::Display for HodlState {
     }
 }
 
+/// Why the plugin itself last released the htlcs of a hold. It is written
+/// right before the state change it explains and stored next to the
+/// `state` key, which keeps its old strings: an expired hodl-invoice is
+/// still `canceled` there.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HodlReason {
+    /// The invoice was about to expire while we held htlcs for it.
+    Expired,
+    /// Held htlcs of an accepted hodl-invoice got too close to their cltv
+    /// expiry and were failed. The hodl-invoice is `open` again, so this
+    /// is never an outcome.
+    CltvTimeout,
+    /// `hodl-channel-policy=cancel` canceled it, an incoming channel was
+    /// closing.
+    ChannelClosing,
+    /// `hodl-channel-policy=cancel` canceled it, a peer we held htlcs
+    /// from was offline for too long.
+    PeerOffline,
+}
+impl HodlReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HodlReason::Expired => "expired",
+            HodlReason::CltvTimeout => "cltv_timeout",
+            HodlReason::ChannelClosing => "channel_closing",
+            HodlReason::PeerOffline => "peer_offline",
+        }
+    }
+}
+impl FromStr for HodlReason {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HodlReason, Error> {
+        match s.to_lowercase().as_str() {
+            "expired" => Ok(HodlReason::Expired),
+            "cltv_timeout" => Ok(HodlReason::CltvTimeout),
+            "channel_closing" => Ok(HodlReason::ChannelClosing),
+            "peer_offline" => Ok(HodlReason::PeerOffline),
+            _ => Err(anyhow!("could not parse HodlReason from string")),
+        }
+    }
+}
+impl fmt::Display for HodlReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The terminal outcome of a hodl-invoice, `None` while it is still
+/// open or accepted.
+pub fn hodl_outcome(state: HodlState, reason: Option<HodlReason>) -> Option<&'static str> {
+    match (state, reason) {
+        (HodlState::Settled, _) => Some("settled"),
+        (HodlState::Canceled, Some(HodlReason::Expired)) => Some("expired"),
+        (HodlState::Canceled, Some(HodlReason::ChannelClosing)) => Some("channel_closing"),
+        (HodlState::Canceled, Some(HodlReason::PeerOffline)) => Some("peer_offline"),
+        (HodlState::Canceled, _) => Some("canceled"),
+        (HodlState::Open | HodlState::Accepted, _) => None,
+    }
+}
+
 async fn datastore_raw(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
     key: Vec<String>,
     string: Option<String>,
     hex: Option<String>,
     mode: Option<DatastoreMode>,
     generation: Option<u64>,
-) -> Result<DatastoreResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
+) -> Result<DatastoreResponse, DatastoreError> {
     let datastore_request = rpc
         .call(Request::Datastore(DatastoreRequest {
             key: key.clone(),
@@ -137,120 +207,273 @@ async fn datastore_raw(
             generation,
         }))
         .await
-        .map_err(|e| anyhow!("Error calling datastore: {:?}", e))?;
-    debug!("datastore_raw: set {:?} to {}", key, string.unwrap());
+        .map_err(|e| match e {
+            RpcCallError::Rpc(e) => match e.code {
+                Some(DATASTORE_UPDATE_WRONG_GENERATION) => DatastoreError::WrongGeneration,
+                Some(DATASTORE_UPDATE_DOES_NOT_EXIST) => DatastoreError::DoesNotExist,
+                _ => DatastoreError::Rejected(anyhow!("Error calling datastore: {:?}", e)),
+            },
+            RpcCallError::Unavailable(e) => DatastoreError::Unavailable(e),
+        })?;
+    debug!("datastore_raw: set {:?} to {:?}", key, string);
     match datastore_request {
         Response::Datastore(info) => Ok(info),
-        e => Err(anyhow!("Unexpected result in datastore: {:?}", e)),
+        e => Err(DatastoreError::Rejected(anyhow!(
+            "Unexpected result in datastore: {:?}",
+            e
+        ))),
     }
 }
 
-async fn datastore_new_state(
-    rpc_path: &PathBuf,
+pub async fn datastore_new_state(
+    rpc: &RpcClient,
     pay_hash: String,
     string: String,
-) -> Result<DatastoreResponse, Error> {
+) -> Result<DatastoreResponse, DatastoreError> {
     datastore_raw(
-        rpc_path,
-        vec![
-            HODLVOICE_PLUGIN_NAME.to_string(),
-            pay_hash,
-            HODLVOICE_DATASTORE_STATE.to_string(),
-        ],
+        rpc,
+        vec![HODLVOICE_STATE_NAME.to_string(), pay_hash],
         Some(string),
         None,
         Some(DatastoreMode::MUST_CREATE),
         None,
     )
-        .await
+    .await
 }
 
 pub async fn datastore_update_state(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
     pay_hash: String,
     string: String,
     generation: u64,
-) -> Result<DatastoreResponse, Error> {
+) -> Result<DatastoreResponse, DatastoreError> {
     datastore_raw(
-        rpc_path,
+        rpc,
+        vec![HODLVOICE_STATE_NAME.to_string(), pay_hash],
+        Some(string),
+        None,
+        Some(DatastoreMode::MUST_REPLACE),
+        Some(generation),
+    )
+    .await
+}
+
+async fn datastore_update_state_forced(
+    rpc: &RpcClient,
+    pay_hash: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_STATE_NAME.to_string(), pay_hash],
+        Some(string),
+        None,
+        Some(DatastoreMode::MUST_REPLACE),
+        None,
+    )
+    .await
+}
+
+pub async fn datastore_htlc_expiry(
+    rpc: &RpcClient,
+    pay_hash: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
         vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
             pay_hash,
-            HODLVOICE_DATASTORE_STATE.to_string(),
+            HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
         ],
         Some(string),
         None,
-        Some(DatastoreMode::MUST_REPLACE),
-        Some(generation),
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
     )
-        .await
+    .await
 }
 
-async fn datastore_update_state_forced(
-    rpc_path: &PathBuf,
+pub async fn datastore_archive(
+    rpc: &RpcClient,
     pay_hash: String,
     string: String,
-) -> Result<DatastoreResponse, Error> {
+) -> Result<DatastoreResponse, DatastoreError> {
     datastore_raw(
-        rpc_path,
+        rpc,
+        vec![HODLVOICE_ARCHIVE_NAME.to_string(), pay_hash],
+        Some(string),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+}
+
+/// Written once lightningd reports the invoice as paid, so a settled
+/// hodl-invoice can be told apart from one lightningd never resolved.
+pub async fn datastore_paid(
+    rpc: &RpcClient,
+    pay_hash: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
         vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
             pay_hash,
-            HODLVOICE_DATASTORE_STATE.to_string(),
+            HODLVOICE_DATASTORE_PAID.to_string(),
         ],
         Some(string),
         None,
-        Some(DatastoreMode::MUST_REPLACE),
+        Some(DatastoreMode::CREATE_OR_REPLACE),
         None,
     )
-        .await
+    .await
 }
 
-pub async fn datastore_htlc_expiry(
-    rpc_path: &PathBuf,
+pub async fn datastore_reason(
+    rpc: &RpcClient,
     pay_hash: String,
+    reason: HodlReason,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_REASON.to_string(),
+        ],
+        Some(reason.to_string()),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+}
+
+pub async fn datastore_onion(
+    rpc: &RpcClient,
+    pay_hash: String,
+    htlc: String,
     string: String,
-) -> Result<DatastoreResponse, Error> {
+) -> Result<DatastoreResponse, DatastoreError> {
     datastore_raw(
-        rpc_path,
+        rpc,
         vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
             pay_hash,
-            HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
+            HODLVOICE_DATASTORE_ONION.to_string(),
+            htlc,
         ],
         Some(string),
         None,
         Some(DatastoreMode::CREATE_OR_REPLACE),
         None,
     )
-        .await
+    .await
+}
+
+pub async fn datastore_outbox(
+    rpc: &RpcClient,
+    id: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_OUTBOX_NAME.to_string(), id],
+        Some(string),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
 }
 
-// pub async fn datastore_update_htlc_expiry(
-//     rpc_path: &PathBuf,
-//     pay_hash: String,
-//     string: String,
-// ) -> Result<DatastoreResponse, Error> {
-//     datastore_raw(
-//         rpc_path,
-//         vec![
-//             HODLVOICE_PLUGIN_NAME.to_string(),
-//             pay_hash,
-//             HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
-//         ],
-//         Some(string),
-//         None,
-//         Some(DatastoreMode::MUST_REPLACE),
-//         None,
-//     )
-//     .await
-// }
+pub async fn datastore_maintenance(
+    rpc: &RpcClient,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_MAINTENANCE_NAME.to_string()],
+        Some(string),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+}
+
+pub async fn datastore_offer(
+    rpc: &RpcClient,
+    offer_id: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_OFFERS_NAME.to_string(), offer_id],
+        Some(string),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+}
+
+pub async fn datastore_new_forward(
+    rpc: &RpcClient,
+    pay_hash: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_FORWARDS_NAME.to_string(), pay_hash],
+        Some(string),
+        None,
+        Some(DatastoreMode::MUST_CREATE),
+        None,
+    )
+    .await
+}
+
+pub async fn datastore_update_forward(
+    rpc: &RpcClient,
+    pay_hash: String,
+    string: String,
+    generation: u64,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_FORWARDS_NAME.to_string(), pay_hash],
+        Some(string),
+        None,
+        Some(DatastoreMode::MUST_REPLACE),
+        Some(generation),
+    )
+    .await
+}
+
+pub async fn datastore_held(
+    rpc: &RpcClient,
+    htlc: String,
+    string: String,
+) -> Result<DatastoreResponse, DatastoreError> {
+    datastore_raw(
+        rpc,
+        vec![HODLVOICE_HELD_NAME.to_string(), htlc],
+        Some(string),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+}
 
 pub async fn list_datastore_raw(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
     key: Option<Vec<String>>,
 ) -> Result<ListdatastoreResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let datastore_request = rpc
         .call(Request::ListDatastore(ListdatastoreRequest { key }))
         .await
@@ -262,18 +485,14 @@ pub async fn list_datastore_raw(
 }
 
 pub async fn list_datastore_state(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
     pay_hash: String,
 ) -> Result<ListdatastoreDatastore, Error> {
     let response = list_datastore_raw(
-        rpc_path,
-        Some(vec![
-            HODLVOICE_PLUGIN_NAME.to_string(),
-            pay_hash.clone(),
-            HODLVOICE_DATASTORE_STATE.to_string(),
-        ]),
+        rpc,
+        Some(vec![HODLVOICE_STATE_NAME.to_string(), pay_hash.clone()]),
     )
-        .await?;
+    .await?;
     let data = response.datastore.first().ok_or_else(|| {
         anyhow!(
             "empty result for list_datastore_state with pay_hash: {}",
@@ -283,16 +502,220 @@ pub async fn list_datastore_state(
     Ok(data.clone())
 }
 
-pub async fn list_datastore_htlc_expiry(rpc_path: &PathBuf, pay_hash: String) -> Result<u32, Error> {
+/// The state of every hodl-invoice, in one `listdatastore` call.
+pub async fn list_datastore_states(rpc: &RpcClient) -> Result<BTreeMap<String, HodlUpdate>, Error> {
+    let response = list_datastore_raw(rpc, Some(vec![HODLVOICE_STATE_NAME.to_string()])).await?;
+    let mut states = BTreeMap::new();
+    for data in response.datastore {
+        let pay_hash = match data.key.get(1) {
+            Some(h) => h.clone(),
+            None => continue,
+        };
+        match data.string.as_deref().map(HodlState::from_str) {
+            Some(Ok(state)) => {
+                states.insert(
+                    pay_hash,
+                    HodlUpdate {
+                        state,
+                        generation: data.generation.unwrap_or(0),
+                    },
+                );
+            }
+            _ => warn!("Unreadable state for pay_hash: {}", pay_hash),
+        }
+    }
+    Ok(states)
+}
+
+/// Every payment hash with a state.
+pub async fn list_datastore_hashes(rpc: &RpcClient) -> Result<Vec<String>, Error> {
+    Ok(
+        list_datastore_raw(rpc, Some(vec![HODLVOICE_STATE_NAME.to_string()]))
+            .await?
+            .datastore
+            .into_iter()
+            .filter_map(|d| d.key.get(1).cloned())
+            .collect(),
+    )
+}
+
+/// Move states written under `hodlvoice/<payment_hash>/state`, where they
+/// were kept before, to `hodlvoice-state/<payment_hash>`. Returns how many
+/// were moved.
+pub async fn migrate_datastore_states(rpc: &RpcClient) -> Result<usize, Error> {
+    let mut moved = 0;
+    let top = list_datastore_raw(rpc, Some(vec![HODLVOICE_PLUGIN_NAME.to_string()])).await?;
+    for pay_hash in top
+        .datastore
+        .into_iter()
+        .filter_map(|d| d.key.get(1).cloned())
+    {
+        let old_key = vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash.clone(),
+            HODLVOICE_DATASTORE_STATE.to_string(),
+        ];
+        let old = match list_datastore_raw(rpc, Some(old_key.clone()))
+            .await?
+            .datastore
+            .into_iter()
+            .next()
+            .and_then(|d| d.string)
+        {
+            Some(s) => s,
+            None => continue,
+        };
+        match datastore_new_state(rpc, pay_hash.clone(), old).await {
+            Ok(_o) => moved += 1,
+            // a state in the new place wins over the old one
+            Err(_e) if list_datastore_state(rpc, pay_hash.clone()).await.is_ok() => (),
+            Err(e) => return Err(e.into()),
+        }
+        del_datastore_raw(rpc, old_key).await?;
+    }
+    Ok(moved)
+}
+
+/// The reason stored for `pay_hash`, `None` if there is none.
+pub async fn list_datastore_reason(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<Option<HodlReason>, Error> {
+    let response = list_datastore_raw(
+        rpc,
+        Some(vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_REASON.to_string(),
+        ]),
+    )
+    .await?;
+    match response.datastore.first().and_then(|d| d.string.as_deref()) {
+        Some(r) => Ok(Some(HodlReason::from_str(r)?)),
+        None => Ok(None),
+    }
+}
+
+/// The `paid` record of `pay_hash`, `None` if lightningd hasn't confirmed
+/// the payment yet.
+pub async fn list_datastore_paid(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<Option<serde_json::Value>, Error> {
+    let response = list_datastore_raw(
+        rpc,
+        Some(vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_PAID.to_string(),
+        ]),
+    )
+    .await?;
+    match response.datastore.first().and_then(|d| d.string.as_deref()) {
+        Some(p) => Ok(Some(serde_json::from_str(p)?)),
+        None => Ok(None),
+    }
+}
+
+/// The onion details of every htlc we held for `pay_hash`.
+pub async fn list_datastore_onions(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let response = list_datastore_raw(
+        rpc,
+        Some(vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_ONION.to_string(),
+        ]),
+    )
+    .await?;
+    let mut onions = Vec::new();
+    for data in response.datastore {
+        if let Some(s) = data.string.as_deref() {
+            onions.push(serde_json::from_str(s)?);
+        }
+    }
+    Ok(onions)
+}
+
+/// The offers registered with `hodl-offer`, by offer id.
+pub async fn list_datastore_offers(
+    rpc: &RpcClient,
+) -> Result<BTreeMap<String, serde_json::Value>, Error> {
+    let response = list_datastore_raw(rpc, Some(vec![HODLVOICE_OFFERS_NAME.to_string()])).await?;
+    let mut offers = BTreeMap::new();
+    for data in response.datastore {
+        if let (Some(offer_id), Some(s)) = (data.key.get(1), data.string.as_deref()) {
+            offers.insert(offer_id.clone(), serde_json::from_str(s)?);
+        }
+    }
+    Ok(offers)
+}
+
+/// State of every forward hold registered with `hodl-forward`.
+pub async fn list_datastore_forwards(
+    rpc: &RpcClient,
+) -> Result<BTreeMap<String, HodlUpdate>, Error> {
+    let response = list_datastore_raw(rpc, Some(vec![HODLVOICE_FORWARDS_NAME.to_string()])).await?;
+    let mut forwards = BTreeMap::new();
+    for data in response.datastore {
+        let pay_hash = match data.key.get(1) {
+            Some(h) => h.clone(),
+            None => continue,
+        };
+        match data.string.as_deref().map(HodlState::from_str) {
+            Some(Ok(state)) => {
+                forwards.insert(
+                    pay_hash,
+                    HodlUpdate {
+                        state,
+                        generation: data.generation.unwrap_or(0),
+                    },
+                );
+            }
+            _ => warn!(
+                "payment_hash: `{}`. Unreadable forward hold state",
+                pay_hash
+            ),
+        }
+    }
+    Ok(forwards)
+}
+
+/// What `hodl-maintenance` stored when it was turned on, `None` if it is off.
+pub async fn list_datastore_maintenance(rpc: &RpcClient) -> Result<Option<String>, Error> {
+    let response =
+        list_datastore_raw(rpc, Some(vec![HODLVOICE_MAINTENANCE_NAME.to_string()])).await?;
+    Ok(response.datastore.first().and_then(|d| d.string.clone()))
+}
+
+/// The htlcs we held when we last wrote them down, keyed by
+/// `<scid>-<htlc id>`.
+pub async fn list_datastore_held(
+    rpc: &RpcClient,
+) -> Result<BTreeMap<String, serde_json::Value>, Error> {
+    let response = list_datastore_raw(rpc, Some(vec![HODLVOICE_HELD_NAME.to_string()])).await?;
+    let mut held = BTreeMap::new();
+    for data in response.datastore {
+        if let (Some(htlc), Some(s)) = (data.key.get(1), data.string.as_deref()) {
+            held.insert(htlc.clone(), serde_json::from_str(s)?);
+        }
+    }
+    Ok(held)
+}
+
+pub async fn list_datastore_htlc_expiry(rpc: &RpcClient, pay_hash: String) -> Result<u32, Error> {
     let response = list_datastore_raw(
-        rpc_path,
+        rpc,
         Some(vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
             pay_hash.clone(),
             HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
         ]),
     )
-        .await?;
+    .await?;
     let data = response
         .datastore
         .first()
@@ -314,11 +737,10 @@ pub async fn list_datastore_htlc_expiry(rpc_path: &PathBuf, pay_hash: String) ->
     Ok(cltv)
 }
 
-async fn del_datastore_raw(
-    rpc_path: &PathBuf,
+pub async fn del_datastore_raw(
+    rpc: &RpcClient,
     key: Vec<String>,
 ) -> Result<DeldatastoreResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let del_datastore_request = rpc
         .call(Request::DelDatastore(DeldatastoreRequest {
             key,
@@ -333,38 +755,111 @@ async fn del_datastore_raw(
 }
 
 pub async fn del_datastore_state(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_STATE_NAME.to_string(), pay_hash]).await
+}
+
+pub async fn del_datastore_htlc_expiry(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(
+        rpc,
+        vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash.clone(),
+            HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
+        ],
+    )
+    .await
+}
+
+pub async fn del_datastore_paid(
+    rpc: &RpcClient,
     pay_hash: String,
 ) -> Result<DeldatastoreResponse, Error> {
     del_datastore_raw(
-        rpc_path,
+        rpc,
         vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
             pay_hash,
-            HODLVOICE_DATASTORE_STATE.to_string(),
+            HODLVOICE_DATASTORE_PAID.to_string(),
         ],
     )
-        .await
+    .await
 }
 
-pub async fn del_datastore_htlc_expiry(
-    rpc_path: &PathBuf,
+pub async fn del_datastore_reason(
+    rpc: &RpcClient,
     pay_hash: String,
 ) -> Result<DeldatastoreResponse, Error> {
     del_datastore_raw(
-        rpc_path,
+        rpc,
         vec![
             HODLVOICE_PLUGIN_NAME.to_string(),
-            pay_hash.clone(),
-            HODLVOICE_DATASTORE_HTLC_EXPIRY.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_REASON.to_string(),
         ],
     )
-        .await
+    .await
 }
 
-fn short_channel_id_to_string(scid: u64) -> String {
-    let block_height = scid >> 40;
-    let tx_index = (scid >> 16) & 0xFFFFFF;
-    let output_index = scid & 0xFFFF;
-    format!("{}x{}x{}", block_height, tx_index, output_index)
+/// Delete the onion details of all htlcs of `pay_hash`, returns how many.
+pub async fn del_datastore_onions(rpc: &RpcClient, pay_hash: String) -> Result<usize, Error> {
+    let response = list_datastore_raw(
+        rpc,
+        Some(vec![
+            HODLVOICE_PLUGIN_NAME.to_string(),
+            pay_hash,
+            HODLVOICE_DATASTORE_ONION.to_string(),
+        ]),
+    )
+    .await?;
+    let mut deleted = 0;
+    for data in response.datastore {
+        del_datastore_raw(rpc, data.key).await?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+pub async fn del_datastore_archive(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_ARCHIVE_NAME.to_string(), pay_hash]).await
+}
+
+pub async fn del_datastore_outbox(
+    rpc: &RpcClient,
+    id: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_OUTBOX_NAME.to_string(), id]).await
+}
+
+pub async fn del_datastore_maintenance(rpc: &RpcClient) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_MAINTENANCE_NAME.to_string()]).await
+}
+
+pub async fn del_datastore_forward(
+    rpc: &RpcClient,
+    pay_hash: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_FORWARDS_NAME.to_string(), pay_hash]).await
+}
+
+pub async fn del_datastore_held(
+    rpc: &RpcClient,
+    htlc: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_HELD_NAME.to_string(), htlc]).await
+}
+
+pub async fn del_datastore_offer(
+    rpc: &RpcClient,
+    offer_id: String,
+) -> Result<DeldatastoreResponse, Error> {
+    del_datastore_raw(rpc, vec![HODLVOICE_OFFERS_NAME.to_string(), offer_id]).await
 }
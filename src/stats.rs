@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::json;
+
+/// Counters for things that went sideways, exposed via `hodl-stats`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub datastore_conflicts: AtomicU64,
+    pub datastore_missing: AtomicU64,
+    pub datastore_unavailable: AtomicU64,
+    pub datastore_rejected: AtomicU64,
+    pub datastore_give_ups: AtomicU64,
+    /// Canceled by us because the invoice was about to expire.
+    pub auto_cancels: AtomicU64,
+    pub cltv_timeouts: AtomicU64,
+    /// Htlcs failed because holding them would break a `hodl-max-*` limit.
+    pub limit_rejections: AtomicU64,
+    /// Htlcs failed because maintenance mode is on.
+    pub maintenance_rejections: AtomicU64,
+    /// Holds on a closing channel or from a peer offline for too long.
+    pub channel_policy: AtomicU64,
+    /// Blocks that replaced ones we had already seen.
+    pub reorgs: AtomicU64,
+    pub gc_runs: AtomicU64,
+    /// Found by the last gc run.
+    pub gc_orphaned: AtomicU64,
+    pub gc_malformed: AtomicU64,
+    pub gc_deleted: AtomicU64,
+    pub settle_confirmed: AtomicU64,
+    /// Settled but not reported paid by lightningd within the deadline.
+    pub settle_overdue: AtomicU64,
+    pub webhook_delivered: AtomicU64,
+    pub webhook_failed: AtomicU64,
+    /// Outbox size at the last delivery run.
+    pub webhook_pending: AtomicU64,
+}
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "datastore": {
+                "conflicts": self.datastore_conflicts.load(Ordering::Relaxed),
+                "missing": self.datastore_missing.load(Ordering::Relaxed),
+                "unavailable": self.datastore_unavailable.load(Ordering::Relaxed),
+                "rejected": self.datastore_rejected.load(Ordering::Relaxed),
+                "give_ups": self.datastore_give_ups.load(Ordering::Relaxed),
+            },
+            "holds": {
+                "auto_cancels": self.auto_cancels.load(Ordering::Relaxed),
+                "cltv_timeouts": self.cltv_timeouts.load(Ordering::Relaxed),
+                "limit_rejections": self.limit_rejections.load(Ordering::Relaxed),
+                "maintenance_rejections": self.maintenance_rejections.load(Ordering::Relaxed),
+                "channel_policy": self.channel_policy.load(Ordering::Relaxed),
+            },
+            "blocks": {
+                "reorgs": self.reorgs.load(Ordering::Relaxed),
+            },
+            "gc": {
+                "runs": self.gc_runs.load(Ordering::Relaxed),
+                "orphaned": self.gc_orphaned.load(Ordering::Relaxed),
+                "malformed": self.gc_malformed.load(Ordering::Relaxed),
+                "deleted": self.gc_deleted.load(Ordering::Relaxed),
+            },
+            "settle": {
+                "confirmed": self.settle_confirmed.load(Ordering::Relaxed),
+                "overdue": self.settle_overdue.load(Ordering::Relaxed),
+            },
+            "webhook": {
+                "delivered": self.webhook_delivered.load(Ordering::Relaxed),
+                "failed": self.webhook_failed.load(Ordering::Relaxed),
+                "pending": self.webhook_pending.load(Ordering::Relaxed),
+            }
+        })
+    }
+}
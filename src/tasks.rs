@@ -1,137 +1,276 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Error;
 use cln_plugin::Plugin;
-use cln_rpc::model::ListinvoicesInvoicesStatus;
+use cln_rpc::model::{ListinvoicesIndex, ListinvoicesInvoices, ListinvoicesInvoicesStatus};
+use cln_rpc::primitives::Amount;
 use log::{debug, info, warn};
+use serde_json::json;
 use tokio::time::{self, Instant};
 
 use crate::{
-    HodlUpdate, PluginState,
+    channels::{check_channels, forget_closed_channels},
+    forwards::refresh_forwards,
+    gc::collect_garbage,
+    notifications,
+    shutdown::forget_gone_held,
     state::{
-        del_datastore_htlc_expiry, del_datastore_state, list_datastore_raw, list_datastore_state,
-        HodlState, HODLVOICE_PLUGIN_NAME,
+        datastore_archive, del_datastore_htlc_expiry, del_datastore_onions, del_datastore_paid,
+        del_datastore_reason, del_datastore_state, hodl_outcome, list_datastore_hashes,
+        list_datastore_reason, list_datastore_state, list_datastore_states, HodlReason, HodlState,
     },
-    util::{listinvoices, make_rpc_path},
+    stats::Stats,
+    util::{invoices_updated_index, listinvoices, listinvoices_page},
+    PluginState,
 };
 
-
 pub async fn lookup_state(plugin: Plugin<PluginState>) -> Result<(), Error> {
     info!("Starting lookup_state");
 
-    let rpc_path = make_rpc_path(&plugin);
+    let rpc = &plugin.state().rpc;
     loop {
         let now = Instant::now();
-        {
-            let states = plugin.state().states.lock().await.clone();
-            let mut map = BTreeMap::new();
-            for (pay_hash, _update) in states.iter() {
-                match list_datastore_state(&rpc_path, pay_hash.clone()).await {
-                    Ok(s) => {
-                        let HodlState = HodlState::from_str(&s.string.unwrap())?;
-                        let gen = if let Some(g) = s.generation { g } else { 0 };
-                        map.insert(
-                            pay_hash.clone(),
-                            HodlUpdate {
-                                state: HodlState,
-                                generation: gen,
-                            },
-                        );
-                    }
-                    Err(e) => warn!(
-                        "Error getting state for pay_hash: {} {}",
-                        pay_hash,
-                        e.to_string()
-                    ),
-                };
-            }
-            let mut states = plugin.state().states.lock().await;
-            for (pay_hash, update) in map.iter() {
-                states.insert(pay_hash.clone(), update.clone());
+        match list_datastore_states(rpc).await {
+            Ok(updates) => {
+                for change in plugin.state().states.apply_updates(&updates).await {
+                    info!(
+                        "payment_hash: `{}`. State changed: {} -> {}",
+                        change.pay_hash,
+                        change.old.state.to_string().to_uppercase(),
+                        change.new.state.to_string().to_uppercase()
+                    );
+                    notifications::state_changed(
+                        &plugin,
+                        &change.pay_hash,
+                        Some(change.old.state),
+                        change.new.state,
+                    )
+                    .await;
+                }
             }
-        }
+            Err(e) => warn!("Error getting hodl-invoice states: {}", e),
+        };
+        check_settlements(&plugin).await;
+        check_channels(&plugin).await;
+        refresh_forwards(&plugin).await;
         debug!("updated states in {}ms", now.elapsed().as_millis());
+        plugin
+            .state()
+            .metrics
+            .lookup_state_duration
+            .observe(now.elapsed().as_secs_f64());
         time::sleep(Duration::from_secs(2)).await;
     }
 }
 
-pub async fn clean_up(plugin: Plugin<PluginState>) -> Result<(), Error> {
-    time::sleep(Duration::from_secs(60)).await;
-    info!("Starting clean_up");
+/// Warn about hodl-invoices we settled that lightningd didn't report as
+/// paid within `hodl-settle-deadline`.
+async fn check_settlements(plugin: &Plugin<PluginState>) {
+    let deadline = plugin.state().config.lock().settle_deadline.1;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    for pay_hash in plugin.state().states.pay_hashes() {
+        let overdue = plugin
+            .state()
+            .states
+            .with_entry(&pay_hash, |e| e.settle_overdue(now, deadline))
+            .await
+            .unwrap_or(false);
+        if overdue {
+            Stats::inc(&plugin.state().stats.settle_overdue);
+            warn!(
+                "payment_hash: `{}`. State=SETTLED for more than {}s but lightningd has not marked the invoice paid!",
+                pay_hash, deadline
+            );
+        }
+    }
+}
 
-    let rpc_path = make_rpc_path(&plugin);
-    loop {
-        let now = Instant::now();
-        // {
-        //     debug!(
-        //         "states: {:?} invoices: {:?} invoice_amts: {:?}",
-        //         plugin.state().states.lock().await,
-        //         plugin.state().invoices.lock(),
-        //         plugin.state().invoice_amts.lock()
-        //     );
-        // }
-        {
-            let unix_now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            let mut node_invoices = listinvoices(&rpc_path, None, None).await?.invoices;
-            node_invoices.retain(|inv| {
-                inv.expires_at + 3_600 <= unix_now
-                    && match inv.status {
-                        ListinvoicesInvoicesStatus::PAID | ListinvoicesInvoicesStatus::EXPIRED => {
-                            true
-                        }
-                        ListinvoicesInvoicesStatus::UNPAID => false,
-                    }
-            });
-            let expired_payment_hashes: Vec<String> = node_invoices
-                .iter()
-                .map(|invoice| invoice.payment_hash.to_string())
-                .collect();
-            // debug!("expired payment_hashes: {:?}", expired_payment_hashes);
-            let datastore =
-                list_datastore_raw(&rpc_path, Some(vec![HODLVOICE_PLUGIN_NAME.to_string()]))
+const CLEANUP_PAGE_SIZE: u32 = 1_000;
+
+/// What `clean_up` remembers between runs: how far into lightningd's
+/// `updated` invoice index it has read and the paid or expired
+/// hodl-invoices still waiting for their grace period.
+#[derive(Debug, Default)]
+struct CleanupCursor {
+    next_updated_index: Option<u64>,
+    finished: BTreeMap<String, ListinvoicesInvoices>,
+}
+impl CleanupCursor {
+    fn note(&mut self, invoice: &ListinvoicesInvoices) {
+        match invoice.status {
+            ListinvoicesInvoicesStatus::PAID | ListinvoicesInvoicesStatus::EXPIRED => {
+                self.finished
+                    .insert(invoice.payment_hash.to_string(), invoice.clone());
+            }
+            ListinvoicesInvoicesStatus::UNPAID => {
+                self.finished.remove(&invoice.payment_hash.to_string());
+            }
+        }
+    }
+}
+
+/// Summary of a finished hodl-invoice kept under `HODLVOICE_ARCHIVE_NAME`
+/// once its live keys are gone.
+fn archive_record(
+    invoice: &ListinvoicesInvoices,
+    state: Option<HodlState>,
+    reason: Option<HodlReason>,
+    archived_at: u64,
+) -> serde_json::Value {
+    let status = match invoice.status {
+        ListinvoicesInvoicesStatus::PAID => "paid",
+        ListinvoicesInvoicesStatus::EXPIRED => "expired",
+        ListinvoicesInvoicesStatus::UNPAID => "unpaid",
+    };
+    let outcome = match (&invoice.status, state) {
+        (ListinvoicesInvoicesStatus::PAID, _) => "paid",
+        (_, Some(state)) => hodl_outcome(state, reason).unwrap_or("expired"),
+        _ => "expired",
+    };
+    json!({
+        "payment_hash": invoice.payment_hash.to_string(),
+        "label": invoice.label,
+        "state": state.map(|s| s.to_string()),
+        "status": status,
+        "outcome": outcome,
+        "reason": reason.map(|r| r.to_string()),
+        "amount_msat": invoice.amount_msat.map(|a| Amount::msat(&a)),
+        "amount_received_msat": invoice.amount_received_msat.map(|a| Amount::msat(&a)),
+        "expires_at": invoice.expires_at,
+        "paid_at": invoice.paid_at,
+        "archived_at": archived_at,
+    })
+}
+
+/// Delete or archive the hodl-invoices that are paid or expired for longer
+/// than `hodl-retention`.
+async fn clean_up_invoices(
+    plugin: &Plugin<PluginState>,
+    cursor: &mut CleanupCursor,
+) -> Result<(), Error> {
+    let rpc = &plugin.state().rpc;
+    let hodl_hashes: BTreeSet<String> = list_datastore_hashes(rpc).await?.into_iter().collect();
+    cursor.finished.retain(|hash, _| hodl_hashes.contains(hash));
+
+    match cursor.next_updated_index {
+        None => {
+            // First run: only ask for the invoices we hold, later
+            // runs follow the `updated` index from here on.
+            let updated_index = invoices_updated_index(rpc).await?;
+            for pay_hash in hodl_hashes.iter() {
+                for invoice in listinvoices(rpc, None, Some(pay_hash.clone()), None)
                     .await?
-                    .datastore;
-            for data in datastore {
-                if expired_payment_hashes.contains(&data.key[1]) {
-                    let _res = del_datastore_htlc_expiry(&rpc_path, data.key[1].clone()).await;
-                    let _res2 = del_datastore_state(&rpc_path, data.key[1].clone()).await;
+                    .invoices
+                {
+                    cursor.note(&invoice);
+                }
+            }
+            cursor.next_updated_index = Some(updated_index + 1);
+        }
+        Some(mut start) => loop {
+            let page = listinvoices_page(rpc, ListinvoicesIndex::UPDATED, start, CLEANUP_PAGE_SIZE)
+                .await?
+                .invoices;
+            let page_start = start;
+            for invoice in page.iter() {
+                if let Some(i) = invoice.updated_index {
+                    start = start.max(i + 1);
                 }
+                if hodl_hashes.contains(&invoice.payment_hash.to_string()) {
+                    cursor.note(invoice);
+                }
+            }
+            cursor.next_updated_index = Some(start);
+            if page.len() < CLEANUP_PAGE_SIZE as usize || start == page_start {
+                break;
             }
+        },
+    }
 
-            plugin
-                .state()
-                .states
-                .lock()
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (retention, archive) = {
+        let config = plugin.state().config.lock();
+        (config.retention.1, config.archive.1)
+    };
+    let mut expired_payment_hashes: Vec<String> = cursor
+        .finished
+        .iter()
+        .filter(|(_, invoice)| invoice.expires_at + retention <= unix_now)
+        .map(|(pay_hash, _)| pay_hash.clone())
+        .collect();
+    if archive {
+        let mut archived = Vec::new();
+        for pay_hash in expired_payment_hashes.into_iter() {
+            let state = match list_datastore_state(rpc, pay_hash.clone()).await {
+                Ok(s) => s.string.and_then(|s| HodlState::from_str(&s).ok()),
+                Err(_e) => None,
+            };
+            let reason = list_datastore_reason(rpc, pay_hash.clone())
                 .await
-                .retain(|hash, _| !expired_payment_hashes.contains(hash));
+                .unwrap_or(None);
+            let record = archive_record(&cursor.finished[&pay_hash], state, reason, unix_now);
+            match datastore_archive(rpc, pay_hash.clone(), record.to_string()).await {
+                Ok(_o) => archived.push(pay_hash),
+                Err(e) => warn!(
+                    "payment_hash: `{}`. Could not archive, keeping it for now: {}",
+                    pay_hash, e
+                ),
+            }
+        }
+        expired_payment_hashes = archived;
+    }
+    for pay_hash in expired_payment_hashes.iter() {
+        let _res = del_datastore_htlc_expiry(rpc, pay_hash.clone()).await;
+        let _res5 = del_datastore_onions(rpc, pay_hash.clone()).await;
+        let _res2 = del_datastore_state(rpc, pay_hash.clone()).await;
+        let _res3 = del_datastore_paid(rpc, pay_hash.clone()).await;
+        let _res4 = del_datastore_reason(rpc, pay_hash.clone()).await;
+        cursor.finished.remove(pay_hash);
+    }
 
-            plugin
-                .state()
-                .invoice_amts
-                .lock()
-                .retain(|hash, _| !expired_payment_hashes.contains(hash));
+    plugin
+        .state()
+        .states
+        .retain(|hash| !expired_payment_hashes.contains(hash));
+    Ok(())
+}
+
+pub async fn clean_up(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    time::sleep(Duration::from_secs(60)).await;
+    info!("Starting clean_up");
 
-            plugin
-                .state()
-                .invoices
-                .lock()
-                .retain(|hash, _| !expired_payment_hashes.contains(hash));
+    let mut cursor = CleanupCursor::default();
+    loop {
+        let now = Instant::now();
+        // `cursor` keeps what we got so far, the next run picks up from there
+        if let Err(e) = clean_up_invoices(&plugin, &mut cursor).await {
+            warn!("Error cleaning up hodl-invoices, retrying later: {}", e);
+        }
+        match collect_garbage(&plugin, true).await {
+            Ok((garbage, _)) if !garbage.is_empty() => warn!(
+                "Found {} orphaned or malformed hodl datastore entries, see `hodl-gc`",
+                garbage.len()
+            ),
+            Ok(_) => (),
+            Err(e) => warn!("Error looking for datastore garbage: {}", e),
         }
-        // {
-        //     debug!(
-        //         "states: {:?} invoices: {:?} invoice_amts: {:?}",
-        //         plugin.state().states.lock().await,
-        //         plugin.state().invoices.lock(),
-        //         plugin.state().invoice_amts.lock()
-        //     );
-        // }
+        forget_closed_channels(&plugin).await;
+        forget_gone_held(&plugin).await;
         info!("cleaned up in {}ms", now.elapsed().as_millis());
+        plugin
+            .state()
+            .metrics
+            .clean_up_duration
+            .observe(now.elapsed().as_secs_f64());
         time::sleep(Duration::from_secs(3_600)).await;
     }
 }
@@ -79,8 +79,8 @@ fn generate_or_load_identity(
         perms.set_mode(0o600);
         std::fs::set_permissions(&key_path, perms)?;
 
-	// Only after changing the permissions we can write the
-	// private key
+        // Only after changing the permissions we can write the
+        // private key
         file.write_all(keypair.serialize_pem().as_bytes())?;
         drop(file);
 
@@ -1,26 +1,74 @@
 use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
-use cln_plugin::{Error, Plugin};
+use cln_plugin::{messages::Configuration, Error};
 use cln_rpc::{
-    model::{ListinvoicesRequest, ListinvoicesResponse},
-    ClnRpc, Request, Response,
+    model::{
+        requests::{WaitIndexname, WaitRequest, WaitSubsystem},
+        DecodepayRequest, DecodepayResponse, GetinfoRequest, GetinfoResponse, InvoiceRequest,
+        InvoiceResponse, ListinvoicesIndex, ListinvoicesRequest, ListinvoicesResponse,
+        ListpeerchannelsRequest, ListpeerchannelsResponse, ListpeersRequest, ListpeersResponse,
+    },
+    primitives::{Amount, AmountOrAny},
+    Request, Response,
 };
 
-use crate::PluginState;
+use crate::rpc::RpcClient;
 
 pub async fn listinvoices(
-    rpc_path: &PathBuf,
+    rpc: &RpcClient,
     label: Option<String>,
     payment_hash: Option<String>,
+    offer_id: Option<String>,
 ) -> Result<ListinvoicesResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let invoice_request = rpc
         .call(Request::ListInvoices(ListinvoicesRequest {
-            label,
+            index: None,
             invstring: None,
+            label,
+            limit: None,
+            offer_id,
             payment_hash,
+            start: None,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling listinvoices: {:?}", e))?;
+    match invoice_request {
+        Response::ListInvoices(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in listinvoices: {:?}", e)),
+    }
+}
+
+pub async fn decodepay(rpc: &RpcClient, bolt11: String) -> Result<DecodepayResponse, Error> {
+    let decode_request = rpc
+        .call(Request::DecodePay(DecodepayRequest {
+            bolt11,
+            description: None,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling decodepay: {:?}", e))?;
+    match decode_request {
+        Response::DecodePay(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in decodepay: {:?}", e)),
+    }
+}
+
+/// Up to `limit` invoices whose `index` is at least `start`.
+pub async fn listinvoices_page(
+    rpc: &RpcClient,
+    index: ListinvoicesIndex,
+    start: u64,
+    limit: u32,
+) -> Result<ListinvoicesResponse, Error> {
+    let invoice_request = rpc
+        .call(Request::ListInvoices(ListinvoicesRequest {
+            index: Some(index),
+            invstring: None,
+            label: None,
+            limit: Some(limit),
             offer_id: None,
+            payment_hash: None,
+            start: Some(start),
         }))
         .await
         .map_err(|e| anyhow!("Error calling listinvoices: {:?}", e))?;
@@ -30,6 +78,89 @@ pub async fn listinvoices(
     }
 }
 
-pub fn make_rpc_path(plugin: &Plugin<PluginState>) -> PathBuf {
-    Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file)
+/// Create an invoice we know the preimage of.
+pub async fn invoice(
+    rpc: &RpcClient,
+    label: String,
+    description: String,
+    amount_msat: u64,
+    expiry: u64,
+    preimage: String,
+) -> Result<InvoiceResponse, Error> {
+    let invoice_request = rpc
+        .call(Request::Invoice(InvoiceRequest {
+            cltv: None,
+            deschashonly: None,
+            expiry: Some(expiry),
+            preimage: Some(preimage),
+            exposeprivatechannels: None,
+            fallbacks: None,
+            amount_msat: AmountOrAny::Amount(Amount::from_msat(amount_msat)),
+            description,
+            label,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling invoice: {:?}", e))?;
+    match invoice_request {
+        Response::Invoice(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in invoice: {:?}", e)),
+    }
+}
+
+/// Current value of the `updated` index of lightningd's invoices.
+pub async fn invoices_updated_index(rpc: &RpcClient) -> Result<u64, Error> {
+    let wait_request = rpc
+        .call(Request::Wait(WaitRequest {
+            subsystem: WaitSubsystem::INVOICES,
+            indexname: WaitIndexname::UPDATED,
+            nextvalue: 0,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling wait: {:?}", e))?;
+    match wait_request {
+        Response::Wait(info) => Ok(info.updated.unwrap_or(0)),
+        e => Err(anyhow!("Unexpected result in wait: {:?}", e)),
+    }
+}
+
+pub async fn listpeerchannels(rpc: &RpcClient) -> Result<ListpeerchannelsResponse, Error> {
+    let channels_request = rpc
+        .call(Request::ListPeerChannels(ListpeerchannelsRequest {
+            id: None,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling listpeerchannels: {:?}", e))?;
+    match channels_request {
+        Response::ListPeerChannels(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in listpeerchannels: {:?}", e)),
+    }
+}
+
+pub async fn getinfo(rpc: &RpcClient) -> Result<GetinfoResponse, Error> {
+    let getinfo_request = rpc
+        .call(Request::Getinfo(GetinfoRequest {}))
+        .await
+        .map_err(|e| anyhow!("Error calling getinfo: {:?}", e))?;
+    match getinfo_request {
+        Response::Getinfo(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in getinfo: {:?}", e)),
+    }
+}
+
+pub async fn listpeers(rpc: &RpcClient) -> Result<ListpeersResponse, Error> {
+    let peers_request = rpc
+        .call(Request::ListPeers(ListpeersRequest {
+            id: None,
+            level: None,
+        }))
+        .await
+        .map_err(|e| anyhow!("Error calling listpeers: {:?}", e))?;
+    match peers_request {
+        Response::ListPeers(info) => Ok(info),
+        e => Err(anyhow!("Unexpected result in listpeers: {:?}", e)),
+    }
+}
+
+pub fn make_rpc_path(configuration: &Configuration) -> PathBuf {
+    Path::new(&configuration.lightning_dir).join(&configuration.rpc_file)
 }
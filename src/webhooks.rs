@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::time;
+
+use crate::{
+    state::{datastore_outbox, del_datastore_outbox, list_datastore_raw, HODLVOICE_OUTBOX_NAME},
+    stats::Stats,
+    PluginState,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const WEBHOOK_MAX_RETRY_DELAY: u64 = 3_600;
+
+/// One event waiting to be delivered to one endpoint, kept under
+/// `HODLVOICE_OUTBOX_NAME` until the endpoint answered with a 2xx.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct OutboxEntry {
+    url: String,
+    attempts: u32,
+    next_attempt_at: u64,
+    event: serde_json::Value,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Outbox keys sort in the order the events happened.
+fn event_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{:020}-{:08x}", nanos, rand::thread_rng().gen::<u32>())
+}
+
+fn retry_delay(attempts: u32) -> u64 {
+    (5u64 << attempts.min(10)).min(WEBHOOK_MAX_RETRY_DELAY)
+}
+
+/// Queue `data` for every configured webhook. The event is in the
+/// datastore before this returns, so it survives a restart.
+pub async fn enqueue(plugin: &Plugin<PluginState>, event_type: &str, data: serde_json::Value) {
+    let urls = plugin.state().config.lock().webhooks.1.clone();
+    if urls.is_empty() {
+        return;
+    }
+    let rpc = &plugin.state().rpc;
+    let id = event_id();
+    let now = unix_now();
+    let event = json!({
+        "id": id,
+        "type": event_type,
+        "created_at": now,
+        "data": data,
+    });
+    for (i, url) in urls.into_iter().enumerate() {
+        let entry = OutboxEntry {
+            url,
+            attempts: 0,
+            next_attempt_at: now,
+            event: event.clone(),
+        };
+        let string = match serde_json::to_string(&entry) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Could not serialize {} webhook: {}", event_type, e);
+                continue;
+            }
+        };
+        if let Err(e) = datastore_outbox(rpc, format!("{}-{}", id, i), string).await {
+            warn!(
+                "Could not queue {} webhook for {}: {}",
+                event_type, entry.url, e
+            );
+        }
+    }
+    plugin.state().webhook_wakeup.notify_one();
+}
+
+pub async fn deliver(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    info!("Starting webhook delivery");
+
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    loop {
+        if let Err(e) = deliver_due(&plugin, &client).await {
+            warn!("Error delivering webhooks: {}", e);
+        }
+        let _res = time::timeout(
+            WEBHOOK_POLL_INTERVAL,
+            plugin.state().webhook_wakeup.notified(),
+        )
+        .await;
+    }
+}
+
+/// Try every outbox entry that is due. Endpoints get their events in
+/// order: once one is not delivered, later ones for the same url wait.
+async fn deliver_due(plugin: &Plugin<PluginState>, client: &reqwest::Client) -> Result<(), Error> {
+    let rpc = &plugin.state().rpc;
+    let stats = &plugin.state().stats;
+    let (secret, urls) = {
+        let config = plugin.state().config.lock();
+        (
+            config.webhook_secret.1.clone().unwrap_or_default(),
+            config.webhooks.1.clone(),
+        )
+    };
+    let mut outbox = list_datastore_raw(rpc, Some(vec![HODLVOICE_OUTBOX_NAME.to_string()]))
+        .await?
+        .datastore;
+    outbox.sort_by(|a, b| a.key.cmp(&b.key));
+    stats
+        .webhook_pending
+        .store(outbox.len() as u64, std::sync::atomic::Ordering::Relaxed);
+
+    let mut blocked = BTreeSet::new();
+    let now = unix_now();
+    for data in outbox {
+        let id = match data.key.get(1) {
+            Some(id) => id.clone(),
+            None => continue,
+        };
+        let mut entry: OutboxEntry = match data.string.as_deref().map(serde_json::from_str) {
+            Some(Ok(e)) => e,
+            _ => {
+                warn!("Unreadable webhook outbox entry `{}`, dropping it", id);
+                del_datastore_outbox(rpc, id).await?;
+                continue;
+            }
+        };
+        if !urls.contains(&entry.url) {
+            info!(
+                "Webhook `{}` is for {}, which is no longer configured, dropping it",
+                id, entry.url
+            );
+            del_datastore_outbox(rpc, id).await?;
+            continue;
+        }
+        if blocked.contains(&entry.url) {
+            continue;
+        }
+        if entry.next_attempt_at > now {
+            blocked.insert(entry.url);
+            continue;
+        }
+        match post(client, &secret, &id, &entry).await {
+            Ok(()) => {
+                debug!("Delivered webhook `{}` to {}", id, entry.url);
+                Stats::inc(&stats.webhook_delivered);
+                del_datastore_outbox(rpc, id).await?;
+            }
+            Err(e) => {
+                Stats::inc(&stats.webhook_failed);
+                entry.attempts += 1;
+                let delay = retry_delay(entry.attempts);
+                entry.next_attempt_at = now + delay;
+                warn!(
+                    "Webhook `{}` to {} failed {} times, retrying in {}s: {}",
+                    id, entry.url, entry.attempts, delay, e
+                );
+                datastore_outbox(rpc, id, serde_json::to_string(&entry)?).await?;
+                blocked.insert(entry.url);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// HMAC-SHA256 keyed with `hodl-webhook-secret` of `<timestamp>.<body>`.
+/// Signing the timestamp lets receivers turn away replayed deliveries.
+fn signature(secret: &str, timestamp: u64, body: &[u8]) -> Result<String, Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| anyhow!("Invalid webhook secret: {}", e))?;
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POST the event with the time of sending in `X-Hodl-Timestamp`, signed
+/// with `X-Hodl-Signature: sha256=<hex>`, see `signature`.
+async fn post(
+    client: &reqwest::Client,
+    secret: &str,
+    id: &str,
+    entry: &OutboxEntry,
+) -> Result<(), Error> {
+    let body = serde_json::to_vec(&entry.event)?;
+    let timestamp = unix_now();
+    let signature = signature(secret, timestamp, &body)?;
+    let response = client
+        .post(&entry.url)
+        .header("Content-Type", "application/json")
+        .header(
+            "X-Hodl-Event",
+            entry
+                .event
+                .get("type")
+                .and_then(|t| t.as_str())
+                .unwrap_or(""),
+        )
+        .header("X-Hodl-Delivery", id)
+        .header("X-Hodl-Timestamp", timestamp.to_string())
+        .header("X-Hodl-Signature", format!("sha256={}", signature))
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("endpoint answered {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let sig = signature("secret", 1_700_000_000, b"{}").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(b"1700000000.{}");
+        assert_eq!(sig, hex::encode(mac.finalize().into_bytes()));
+        assert_ne!(sig, signature("secret", 1_700_000_001, b"{}").unwrap());
+    }
+}